@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// A fact given on the command line with `--assume`, replacing whatever the
+/// system would report. Useful to get deterministic dry runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Assumption {
+    /// `<probe>:<argument>=<result>`, e.g. `ping:10.0.0.1=down`
+    Probe { probe: String, argument: String, result: bool },
+    /// `var:<name>=<value>`, e.g. `var:env_USER=ci`
+    Var { name: String, value: String },
+}
+
+impl FromStr for Assumption {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = s.split_once(':')
+            .ok_or(format!("Assumption '{}' must be of the form <kind>:<argument>=<value>", s))?;
+
+        if kind == "var" {
+            let (name, value) = rest.split_once('=')
+                .ok_or(format!("Variable assumption '{}' must be of the form var:<name>=<value>", s))?;
+            return Ok(Assumption::Var { name: name.to_owned(), value: value.to_owned() });
+        }
+
+        // Arguments such as paths may contain '=', results never do
+        let (argument, result) = rest.rsplit_once('=')
+            .ok_or(format!("Assumption '{}' must be of the form <kind>:<argument>=<value>", s))?;
+        let result = match result.to_lowercase().as_str() {
+            "true" | "up" | "yes" | "on" | "1" => true,
+            "false" | "down" | "no" | "off" | "0" => false,
+            _ => return Err(format!("Invalid result '{}' in assumption '{}', expected true/false or up/down", result, s)),
+        };
+        Ok(Assumption::Probe { probe: kind.to_owned(), argument: argument.to_owned(), result })
+    }
+}
+
+/// Every assumption in effect for an evaluation
+#[derive(Debug, Clone, Default)]
+pub struct Assumptions {
+    probes: HashMap<(String, String), bool>,
+    vars: HashMap<String, String>,
+}
+
+impl Assumptions {
+    pub fn new(assumptions: Vec<Assumption>) -> Self {
+        let mut res = Assumptions::default();
+        for assumption in assumptions {
+            res.add(assumption);
+        }
+        res
+    }
+
+    pub fn add(&mut self, assumption: Assumption) {
+        match assumption {
+            Assumption::Probe { probe, argument, result } => {
+                self.probes.insert((probe, argument), result);
+            },
+            Assumption::Var { name, value } => {
+                self.vars.insert(name, value);
+            },
+        }
+    }
+
+    /// Assumed result of `try <probe> <argument>`, if any
+    pub fn probe(&self, probe: &str, argument: &str) -> Option<bool> {
+        self.probes.get(&(probe.to_owned(), argument.to_owned())).copied()
+    }
+
+    pub fn var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|value| value.as_str())
+    }
+
+    pub fn vars(&self) -> &HashMap<String, String> {
+        &self.vars
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.probes.is_empty() && self.vars.is_empty()
+    }
+}
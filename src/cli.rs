@@ -1,4 +1,9 @@
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap::parser::ValueSource;
+
+use std::path::PathBuf;
+
+use drophost::config::{Config, DEFAULT_CONFIG_PATH};
 
 #[derive(Parser, Debug)]
 #[clap(version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"), about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -19,6 +24,33 @@ pub struct Opts {
     #[clap(long, default_value = "false")]
     pub daemon: bool,
 
+    /// Config file, command line flags take precedence over it [default: /etc/drophost/drophost.toml]
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+
+    /// Directory containing the hosts file and the drop-in directory [default: /etc, or ./output in a dry run]
+    #[clap(long)]
+    pub root: Option<PathBuf>,
+
+    /// Drop-in directory to read [default: <ROOT>/hosts.d]
+    #[clap(long)]
+    pub config_dir: Option<PathBuf>,
+
+    /// Lower priority drop-in directory, whose files are only read if the drop-in directory doesn't
+    /// have a file with the same name. Can be repeated, from highest to lowest priority
+    /// [default: /run/hosts.d and /usr/lib/hosts.d, next to <ROOT> if it is named 'etc', in <ROOT> otherwise]
+    #[clap(long)]
+    pub fallback_dir: Vec<PathBuf>,
+
+    /// Only read drop-in files whose name matches this pattern. Hidden files and files left
+    /// behind by editors and package managers are never read
+    #[clap(long, default_value = drophost::parser::DEFAULT_PATTERN, value_parser = glob::Pattern::new)]
+    pub pattern: glob::Pattern,
+
+    /// Hosts file to write [default: <ROOT>/hosts]
+    #[clap(long)]
+    pub output: Option<PathBuf>,
+
     /// PID file location (only used when running as a daemon)
     #[clap(short, long, default_value = "/run/drophost.pid")]
     pub pid_file: String,
@@ -27,6 +59,94 @@ pub struct Opts {
     #[clap(short, long, default_value = "false")]
     pub backup: bool,
 
+    /// List the conditions available to 'try' statements and exit
+    #[clap(long, default_value = "false")]
+    pub list_probes: bool,
+
+    /// How long results of network probes such as 'try ping' are reused (e.g. 30s, 5m)
+    #[clap(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    pub probe_cache_ttl: std::time::Duration,
+
+    /// Maximum time to wait for network probes, which are all run at the same time
+    #[clap(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    pub probe_deadline: std::time::Duration,
+
+    /// Number of echo requests sent by 'try ping', the host is reachable if any is answered
+    #[cfg(feature = "ping")]
+    #[clap(long, default_value = "1")]
+    pub ping_count: u16,
+
+    /// Time to wait for each reply to 'try ping'
+    #[cfg(feature = "ping")]
+    #[clap(long, default_value = "1s", value_parser = humantime::parse_duration)]
+    pub ping_timeout: std::time::Duration,
+
+    /// TTL (or hop limit for IPv6) of the requests sent by 'try ping'
+    #[cfg(feature = "ping")]
+    #[clap(long, default_value = "64")]
+    pub ping_ttl: u32,
+
+    /// Assume the result of a condition or the value of a variable instead of checking it,
+    /// e.g. ping:10.0.0.1=down, file:/etc/vpn.up=true or var:env_USER=ci. Can be repeated
+    #[clap(long, value_parser = clap::value_parser!(drophost::assume::Assumption))]
+    pub assume: Vec<drophost::assume::Assumption>,
+
+    /// What to do when a hostname is mapped to different IPs, or twice to the same IP
+    #[clap(long, value_enum, default_value_t = drophost::conflicts::ConflictPolicy::KeepBoth)]
+    pub conflicts: drophost::conflicts::ConflictPolicy,
+
+    /// Format of the generated file
+    #[clap(long, value_enum, default_value_t = drophost::writer::Format::Hosts)]
+    pub format: drophost::writer::Format,
+
+    /// Origin of the zone written by the bind format, as in 'lan'. Without it, only the records
+    /// are written, to be included in a zone file with '$INCLUDE'
+    #[clap(long)]
+    pub zone: Option<String>,
+
+    /// Write to this file instead of the hosts file, as in '/etc/dnsmasq.d/lan.conf,format=dnsmasq,tag=lan'.
+    /// Only entries with one of the given tags are written, all of them if none is given. Targets can also
+    /// set zone=<zone>, sort=<order>, provenance, managed-block, group and split-families. Can be repeated
+    #[clap(long, value_parser = clap::value_parser!(drophost::targets::Target))]
+    pub target: Vec<drophost::targets::Target>,
+
+    /// Add a comment with the file and line each entry comes from to the hosts file
+    #[clap(long, default_value = "false", overrides_with = "no_provenance")]
+    pub provenance: bool,
+
+    /// Don't add provenance comments, even if the config file does
+    #[clap(long, default_value = "false", overrides_with = "provenance")]
+    pub no_provenance: bool,
+
+    /// Only replace the lines between '# BEGIN drophost' and '# END drophost' in the hosts file,
+    /// keeping the lines added by other tools
+    #[clap(long, default_value = "false", overrides_with = "no_managed_block")]
+    pub managed_block: bool,
+
+    /// Replace the whole hosts file, even if the config file sets managed-block
+    #[clap(long, default_value = "false", overrides_with = "managed_block")]
+    pub no_managed_block: bool,
+
+    /// Write every hostname of an IP address on a single line, split when it gets too long
+    #[clap(long, default_value = "false", overrides_with = "no_group")]
+    pub group: bool,
+
+    /// Write each hostname on its own line, even if the config file sets group
+    #[clap(long, default_value = "false", overrides_with = "group")]
+    pub no_group: bool,
+
+    /// Order of the entries in the hosts file
+    #[clap(long, value_enum, default_value_t = drophost::writer::SortOrder::Source)]
+    pub sort: drophost::writer::SortOrder,
+
+    /// Write IPv4 and IPv6 entries in separate sections
+    #[clap(long, default_value = "false", overrides_with = "no_split_families")]
+    pub split_families: bool,
+
+    /// Write IPv4 and IPv6 entries together, even if the config file sets split-families
+    #[clap(long, default_value = "false", overrides_with = "split_families")]
+    pub no_split_families: bool,
+
     /// Log level: trace, debug, info, warn, error, off
     #[clap(short, long, default_value = "info")]
     pub log_level: log::LevelFilter,
@@ -34,4 +154,100 @@ pub struct Opts {
     /// Log file location, must be writable by the user running drophost
     #[clap(long, default_value = "/var/log/drophost.log")]
     pub log_file: String,
-}
\ No newline at end of file
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the test cases of a drop-in directory and report the differences
+    Test {
+        /// Drop-in directory to test
+        dir: String,
+
+        /// Directory containing the '*.toml' test cases [default: <DIR>/../hosts.tests]
+        #[clap(long)]
+        cases: Option<String>,
+    },
+
+    /// List the drop-in files in the order they are read, with their state, their priority
+    /// and the number of entries each contributes
+    List,
+
+    /// Stop reading a drop-in file and update the hosts file
+    Disable {
+        /// Path of the file relative to its drop-in directory, with or without its extension
+        name: String,
+    },
+
+    /// Read a disabled drop-in file again and update the hosts file
+    Enable {
+        /// Path of the file relative to its drop-in directory, with or without its extension
+        name: String,
+    },
+
+    /// Show every decision that affected the entries of a hostname
+    Explain {
+        hostname: String,
+    },
+
+    /// Print the entries a name or an IP address resolves to, without writing anything
+    Query {
+        /// Hostname to look up
+        #[clap(required_unless_present = "ip", conflicts_with = "ip")]
+        name: Option<String>,
+
+        /// Look up the hostnames of an IP address instead
+        #[clap(long)]
+        ip: Option<String>,
+
+        /// Print the entries as JSON
+        #[clap(long, default_value = "false")]
+        json: bool,
+    },
+}
+
+// Sets each field from the config file, unless it was given on the command line
+macro_rules! merge {
+    ($opts:ident, $config:ident, $matches:ident, $($field:ident),*) => {
+        $(
+            if $matches.value_source(stringify!($field)) != Some(ValueSource::CommandLine) {
+                if let Some(value) = $config.$field {
+                    $opts.$field = value.into();
+                }
+            }
+        )*
+    };
+}
+
+impl Opts {
+    /// Parses the command line and fills in the settings it doesn't give from the config file
+    pub fn load() -> Self {
+        let matches = Opts::command().get_matches();
+        let mut opts = Opts::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+        let config = match &opts.config {
+            Some(path) => Config::load(path, true),
+            None => Config::load(PathBuf::from(DEFAULT_CONFIG_PATH).as_path(), false),
+        };
+        let config = config.unwrap_or_else(|e| {
+            Opts::command().error(clap::error::ErrorKind::Io, e).exit()
+        });
+
+        merge!(opts, config, matches,
+            root, config_dir, fallback_dir, pattern, output, pid_file,
+            probe_cache_ttl, probe_deadline, assume,
+            conflicts, format, zone, target, provenance, managed_block, group, sort, split_families,
+            log_level, log_file);
+        #[cfg(feature = "ping")]
+        merge!(opts, config, matches, ping_count, ping_timeout, ping_ttl);
+
+        // The '--no-*' flags turn off what the config file turns on
+        opts.provenance &= !opts.no_provenance;
+        opts.managed_block &= !opts.no_managed_block;
+        opts.group &= !opts.no_group;
+        opts.split_families &= !opts.no_split_families;
+        opts
+    }
+}
@@ -0,0 +1,134 @@
+use chrono::{Datelike, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+
+use std::str::FromStr;
+
+/// Source of the current local time used by time-based `try` conditions.
+/// Tests can swap in a `FixedClock` to get deterministic results.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> NaiveDateTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+}
+
+pub struct FixedClock(pub NaiveDateTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> NaiveDateTime {
+        self.0
+    }
+}
+
+/// The earliest of two optional points in time
+pub fn earliest(a: Option<NaiveDateTime>, b: Option<NaiveDateTime>) -> Option<NaiveDateTime> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// A daily time window such as `09:00-17:30`. Windows where the end is
+/// before the start wrap around midnight (e.g. `22:00-06:00`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// The next moment after `now` at which the window opens or closes.
+    pub fn next_boundary(&self, now: NaiveDateTime) -> NaiveDateTime {
+        [self.start, self.end]
+            .iter()
+            .map(|time| {
+                let candidate = now.date().and_time(*time);
+                if candidate <= now {
+                    candidate + Duration::days(1)
+                } else {
+                    candidate
+                }
+            })
+            .min()
+            .expect("This should never happen! (TimeWindow::next_boundary)")
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-')
+            .ok_or(format!("Time window '{}' must be of the form HH:MM-HH:MM", s))?;
+        let start = NaiveTime::parse_from_str(start, "%H:%M")
+            .map_err(|_| format!("Invalid time '{}'", start))?;
+        let end = NaiveTime::parse_from_str(end, "%H:%M")
+            .map_err(|_| format!("Invalid time '{}'", end))?;
+        Ok(TimeWindow { start, end })
+    }
+}
+
+/// A set of weekdays such as `mon-fri` or `sat,sun`. Ranges may wrap
+/// around the end of the week (e.g. `fri-mon`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DayWindow {
+    // Indexed by `Weekday::num_days_from_monday`
+    pub days: [bool; 7],
+}
+
+impl DayWindow {
+    pub fn contains(&self, day: Weekday) -> bool {
+        self.days[day.num_days_from_monday() as usize]
+    }
+
+    /// The next midnight after `now` at which the day set is entered or left,
+    /// or `None` if the window covers either every day or no day at all.
+    pub fn next_boundary(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        let today = self.contains(now.weekday());
+        (1..=7)
+            .map(|offset| now.date() + Duration::days(offset))
+            .find(|date: &NaiveDate| self.contains(date.weekday()) != today)
+            .map(|date| date.and_time(NaiveTime::MIN))
+    }
+}
+
+impl FromStr for DayWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_day = |day: &str| {
+            Weekday::from_str(day).map_err(|_| format!("Invalid day '{}'", day))
+        };
+
+        let mut days = [false; 7];
+        for part in s.split(',') {
+            match part.split_once('-') {
+                Some((from, to)) => {
+                    let mut day = parse_day(from)?;
+                    let to = parse_day(to)?;
+                    days[day.num_days_from_monday() as usize] = true;
+                    while day != to {
+                        day = day.succ();
+                        days[day.num_days_from_monday() as usize] = true;
+                    }
+                }
+                None => {
+                    days[parse_day(part)?.num_days_from_monday() as usize] = true;
+                }
+            }
+        }
+        Ok(DayWindow { days })
+    }
+}
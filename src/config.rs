@@ -0,0 +1,114 @@
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer};
+
+use crate::assume::Assumption;
+use crate::conflicts::ConflictPolicy;
+use crate::targets::Target;
+use crate::writer::{Format, SortOrder};
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/drophost/drophost.toml";
+
+/// Settings read from the drophost config file. Keys are named after the command line
+/// flags, which take precedence over them:
+///
+/// ```toml
+/// root = "/srv/lab/etc"
+/// probe-deadline = "2s"
+/// conflicts = "last-wins"
+///
+/// [[target]]
+/// path = "/etc/hosts"
+/// managed-block = true
+///
+/// [[target]]
+/// path = "/etc/dnsmasq.d/lan.conf"
+/// format = "dnsmasq"
+/// filter = { tags = ["lan"] }
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Directory containing the hosts file and the drop-in directory
+    pub root: Option<PathBuf>,
+    /// Drop-in directory, '<root>/hosts.d' by default
+    pub config_dir: Option<PathBuf>,
+    /// Lower priority drop-in directories, from highest to lowest priority
+    pub fallback_dir: Option<Vec<PathBuf>>,
+    /// Pattern of the drop-in file names, '*.conf' by default
+    #[serde(default, deserialize_with = "from_str")]
+    pub pattern: Option<glob::Pattern>,
+    /// Hosts file, '<root>/hosts' by default
+    pub output: Option<PathBuf>,
+    pub pid_file: Option<String>,
+
+    #[serde(default, deserialize_with = "from_str")]
+    pub probe_cache_ttl: Option<humantime::Duration>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub probe_deadline: Option<humantime::Duration>,
+    pub ping_count: Option<u16>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub ping_timeout: Option<humantime::Duration>,
+    pub ping_ttl: Option<u32>,
+    #[serde(default, deserialize_with = "from_str_vec")]
+    pub assume: Option<Vec<Assumption>>,
+
+    #[serde(default, deserialize_with = "value_enum")]
+    pub conflicts: Option<ConflictPolicy>,
+    #[serde(default, deserialize_with = "value_enum")]
+    pub format: Option<Format>,
+    /// Origin of the zone written by the bind format
+    pub zone: Option<String>,
+    pub target: Option<Vec<Target>>,
+    pub provenance: Option<bool>,
+    pub managed_block: Option<bool>,
+    pub group: Option<bool>,
+    #[serde(default, deserialize_with = "value_enum")]
+    pub sort: Option<SortOrder>,
+    pub split_families: Option<bool>,
+
+    #[serde(default, deserialize_with = "from_str")]
+    pub log_level: Option<log::LevelFilter>,
+    pub log_file: Option<String>,
+}
+
+impl Config {
+    /// Reads the config file. A missing file is only an error if `required` is set,
+    /// otherwise the defaults are used.
+    pub fn load(path: &Path, required: bool) -> Result<Self, String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => return Ok(Config::default()),
+            Err(e) => return Err(format!("Could not read config file '{}': {}", path.display(), e)),
+        };
+        toml::from_str(&contents)
+            .map_err(|e| format!("Could not parse config file '{}': {}", path.display(), e))
+    }
+}
+
+// Values given as strings in the same format as on the command line
+fn from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where D: Deserializer<'de>, T: FromStr, T::Err: Display {
+    let value = String::deserialize(deserializer)?;
+    T::from_str(&value).map(Some).map_err(serde::de::Error::custom)
+}
+
+fn from_str_vec<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+    where D: Deserializer<'de>, T: FromStr, T::Err: Display {
+    let values = Vec::<String>::deserialize(deserializer)?;
+    values.iter()
+        .map(|value| T::from_str(value).map_err(serde::de::Error::custom))
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+fn value_enum<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where D: Deserializer<'de>, T: ValueEnum {
+    let value = String::deserialize(deserializer)?;
+    T::from_str(&value, true).map(Some).map_err(serde::de::Error::custom)
+}
@@ -0,0 +1,108 @@
+use log::*;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::net::IpAddr;
+
+use crate::types::{Host, Hosts};
+
+/// What to do when a hostname is mapped more than once. A hostname mapped
+/// to both an IPv4 and an IPv6 address is never a conflict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ConflictPolicy {
+    /// Keep the entry read first
+    FirstWins,
+    /// Keep the entry read last
+    LastWins,
+    /// Keep every entry, only warn about conflicts
+    #[default]
+    KeepBoth,
+    /// Refuse to write the hosts file
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// Same hostname and same IP
+    Duplicate,
+    /// Same hostname, different IPs of the same family
+    DifferentIp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub kind: ConflictKind,
+    pub first: Host,
+    pub second: Host,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let location = |host: &Host| match &host.source {
+            Some(source) => source.to_string(),
+            None => "unknown location".to_string(),
+        };
+        match self.kind {
+            ConflictKind::Duplicate => write!(f, "'{}' is mapped to {} twice ({} and {})",
+                self.first.hostname, self.first.ip, location(&self.first), location(&self.second)),
+            ConflictKind::DifferentIp => write!(f, "'{}' is mapped to {} ({}) and {} ({})",
+                self.first.hostname, self.first.ip, location(&self.first), self.second.ip, location(&self.second)),
+        }
+    }
+}
+
+// IPv4 and IPv6 entries never conflict, unparsable IPs are grouped together
+fn family(ip: &str) -> u8 {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(_)) => 4,
+        Ok(IpAddr::V6(_)) => 6,
+        Err(_) => 0,
+    }
+}
+
+/// Finds every later entry that conflicts with an earlier one for the same hostname
+pub fn find_conflicts(hosts: &Hosts) -> Vec<Conflict> {
+    let mut seen: HashMap<(&str, u8), &Host> = HashMap::new();
+    let mut conflicts = vec![];
+    for host in &hosts.hosts {
+        let key = (host.hostname.as_str(), family(&host.ip));
+        match seen.get(&key) {
+            Some(first) => {
+                let kind = if first.ip == host.ip { ConflictKind::Duplicate } else { ConflictKind::DifferentIp };
+                conflicts.push(Conflict { kind, first: (*first).clone(), second: host.clone() });
+            },
+            None => {
+                seen.insert(key, host);
+            }
+        }
+    }
+    conflicts
+}
+
+/// Reports conflicts and removes the losing entries according to the policy.
+/// With `ConflictPolicy::Error`, the hosts are left untouched.
+pub fn resolve(hosts: &mut Hosts, policy: ConflictPolicy) -> Vec<Conflict> {
+    let conflicts = find_conflicts(hosts);
+    for conflict in &conflicts {
+        match policy {
+            ConflictPolicy::Error => error!("Conflicting entries: {}", conflict),
+            _ => warn!("Conflicting entries: {}", conflict),
+        }
+    }
+
+    match policy {
+        ConflictPolicy::FirstWins => keep_first(hosts),
+        ConflictPolicy::LastWins => {
+            hosts.hosts.reverse();
+            keep_first(hosts);
+            hosts.hosts.reverse();
+        },
+        ConflictPolicy::KeepBoth | ConflictPolicy::Error => {},
+    }
+    conflicts
+}
+
+fn keep_first(hosts: &mut Hosts) {
+    let mut seen = HashSet::new();
+    hosts.hosts.retain(|host| seen.insert((host.hostname.clone(), family(&host.ip))));
+}
@@ -0,0 +1,82 @@
+use std::fmt::Display;
+
+use crate::types::Source;
+
+/// An 'if' or 'try' statement enclosing a traced line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub source: Source,
+    pub statement: String,
+    pub result: bool,
+    // False when an enclosing block was already false
+    pub evaluated: bool,
+    pub in_else: bool,
+    /// Variable values and probe results the decision was based on
+    pub details: Vec<String>,
+}
+
+impl Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.evaluated {
+            return write!(f, "{}: '{}' was not evaluated", self.source, self.statement);
+        }
+        write!(f, "{}: '{}' is {}", self.source, self.statement, self.result)?;
+        if self.in_else {
+            write!(f, ", in the else branch")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Added,
+    Overridden,
+    Removed,
+    Skipped,
+    Condition(bool),
+    Statement,
+    Error,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Added => write!(f, "added"),
+            Outcome::Overridden => write!(f, "replaced previous entries"),
+            Outcome::Removed => write!(f, "removed previous entries"),
+            Outcome::Skipped => write!(f, "skipped"),
+            Outcome::Condition(res) => write!(f, "condition is {}", res),
+            Outcome::Statement => write!(f, "evaluated"),
+            Outcome::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A line mentioning the explained hostname, with everything that decided its fate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub source: Source,
+    pub line: String,
+    pub outcome: Outcome,
+    /// Variable values used by the line itself
+    pub details: Vec<String>,
+    /// Enclosing conditions, outermost first
+    pub decisions: Vec<Decision>,
+}
+
+impl Display for TraceEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}: '{}' => {}", self.source, self.line, self.outcome)?;
+        for detail in &self.details {
+            writeln!(f, "    {}", detail)?;
+        }
+        for decision in &self.decisions {
+            writeln!(f, "    because {}", decision)?;
+            for detail in &decision.details {
+                writeln!(f, "        {}", detail)?;
+            }
+        }
+        Ok(())
+    }
+}
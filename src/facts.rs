@@ -0,0 +1,144 @@
+use log::*;
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::sync::Mutex;
+
+use nix::sys::utsname::uname;
+use nix::unistd::Uid;
+use once_cell::sync::Lazy;
+
+/// A hostname and its canonical name, if the resolver knows one
+type NameLookup = (String, Option<String>);
+
+/// The last hostname looked up, as every run, including the rescans of watch mode,
+/// reads the facts again and the lookup can block on the network
+static CANONICAL_NAME: Lazy<Mutex<Option<NameLookup>>> = Lazy::new(|| Mutex::new(None));
+
+/// Provides facts about the system drophost is running on. These are made
+/// available to configuration files as `sys_*` variables, which unlike
+/// `env_*` variables don't depend on the environment drophost was started in.
+pub trait FactsProvider {
+    fn hostname(&self) -> Option<String>;
+    fn fqdn(&self) -> Option<String>;
+    fn os_id(&self) -> Option<String>;
+    fn machine_id(&self) -> Option<String>;
+    fn arch(&self) -> Option<String>;
+    fn kernel(&self) -> Option<String>;
+    fn uid(&self) -> Option<String>;
+
+    /// Collect every available fact as a variable map. Facts that could not
+    /// be determined are left undefined so `try var` can detect them.
+    fn vars(&self) -> HashMap<String, String> {
+        let facts = [
+            ("sys_hostname", self.hostname()),
+            ("sys_fqdn", self.fqdn()),
+            ("sys_os_id", self.os_id()),
+            ("sys_machine_id", self.machine_id()),
+            ("sys_arch", self.arch()),
+            ("sys_kernel", self.kernel()),
+            ("sys_uid", self.uid()),
+        ];
+
+        let mut vars = HashMap::new();
+        for (key, value) in facts {
+            match value {
+                Some(value) => {
+                    vars.insert(key.to_owned(), value);
+                },
+                None => debug!("Could not determine system fact '{}'", key),
+            }
+        }
+        vars
+    }
+}
+
+/// Reads facts from the running system
+pub struct SystemFacts;
+
+impl FactsProvider for SystemFacts {
+    fn hostname(&self) -> Option<String> {
+        let uts = uname().ok()?;
+        Some(uts.nodename().to_string_lossy().into_owned())
+    }
+
+    fn fqdn(&self) -> Option<String> {
+        let hostname = self.hostname()?;
+        // Ask the resolver for the canonical name, falling back to the plain hostname
+        Some(cached_canonical_name(&hostname).unwrap_or(hostname))
+    }
+
+    fn os_id(&self) -> Option<String> {
+        let contents = fs::read_to_string("/etc/os-release")
+            .or_else(|_| fs::read_to_string("/usr/lib/os-release"))
+            .ok()?;
+        parse_os_release_id(&contents)
+    }
+
+    fn machine_id(&self) -> Option<String> {
+        let id = fs::read_to_string("/etc/machine-id").ok()?;
+        let id = id.trim();
+        if id.is_empty() {
+            return None;
+        }
+        Some(id.to_owned())
+    }
+
+    fn arch(&self) -> Option<String> {
+        let uts = uname().ok()?;
+        Some(uts.machine().to_string_lossy().into_owned())
+    }
+
+    fn kernel(&self) -> Option<String> {
+        let uts = uname().ok()?;
+        Some(uts.release().to_string_lossy().into_owned())
+    }
+
+    fn uid(&self) -> Option<String> {
+        Some(Uid::current().as_raw().to_string())
+    }
+}
+
+/// Extract the `ID` field from the contents of an os-release file
+pub fn parse_os_release_id(contents: &str) -> Option<String> {
+    contents.lines()
+        .filter_map(|line| line.trim().strip_prefix("ID="))
+        .map(|value| value.trim_matches(|c| c == '"' || c == '\'').to_owned())
+        .next()
+}
+
+/// Only asks the resolver again if the hostname changed since the last lookup
+fn cached_canonical_name(hostname: &str) -> Option<String> {
+    let mut cached = CANONICAL_NAME.lock().unwrap();
+    match &*cached {
+        Some((name, canonical)) if name == hostname => canonical.clone(),
+        _ => {
+            let canonical = canonical_name(hostname);
+            *cached = Some((hostname.to_owned(), canonical.clone()));
+            canonical
+        },
+    }
+}
+
+fn canonical_name(hostname: &str) -> Option<String> {
+    let node = CString::new(hostname).ok()?;
+    let mut hints: libc::addrinfo = unsafe { std::mem::zeroed() };
+    hints.ai_flags = libc::AI_CANONNAME;
+    hints.ai_family = libc::AF_UNSPEC;
+
+    let mut res: *mut libc::addrinfo = std::ptr::null_mut();
+    let ret = unsafe { libc::getaddrinfo(node.as_ptr(), std::ptr::null(), &hints, &mut res) };
+    if ret != 0 || res.is_null() {
+        return None;
+    }
+
+    let canon = unsafe { (*res).ai_canonname };
+    let name = if canon.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(canon) }.to_string_lossy().into_owned())
+    };
+    unsafe { libc::freeaddrinfo(res) };
+    name
+}
@@ -0,0 +1,20 @@
+#![allow(dead_code)]
+#![allow(unused_imports)]
+
+pub mod writer;
+pub mod types;
+pub mod parser;
+pub mod clock;
+pub mod facts;
+pub mod probes;
+pub mod assume;
+pub mod testing;
+pub mod conflicts;
+pub mod explain;
+pub mod targets;
+pub mod config;
+#[cfg(feature = "ping")]
+pub mod ping;
+#[macro_use]
+pub mod utils;
+mod tests;
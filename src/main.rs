@@ -2,14 +2,16 @@
 #![allow(unused_imports)]
 
 mod cli;
-mod writer;
-mod types;
-mod parser;
-#[macro_use]
-mod utils;
-mod tests;
-
-use std::path::{self, Path};
+
+use drophost::{assume, clock, parser, probes, testing, utils, writer};
+
+use std::path::{self, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use chrono::NaiveDateTime;
+use clock::Clock;
+use drophost::conflicts::ConflictPolicy;
+use drophost::targets::Target;
 use notify::{RecommendedWatcher, RecursiveMode, recommended_watcher, Watcher};
 
 use clap::Parser;
@@ -31,10 +33,17 @@ compile_warning!(The "interface" feature is not currently implemented.);
 #[cfg(feature = "range")]
 compile_warning!(The "range" feature is not currently implemented.);
 
-static OPTS: Lazy<cli::Opts> = Lazy::new(|| cli::Opts::parse());
+// Vendor and runtime drop-in directories, '/etc/hosts.d' takes precedence over them
+const SYSTEM_FALLBACK_DIRS: [&str; 2] = ["run/hosts.d", "usr/lib/hosts.d"];
+
+// Directories whose files can only be changed by root
+const SYSTEM_PATHS: [&str; 4] = ["/etc", "/run", "/usr", "/var"];
 
-#[tokio::main]
-async fn main() {
+static OPTS: Lazy<cli::Opts> = Lazy::new(cli::Opts::load);
+// Shared between runs so that watch mode doesn't repeat slow probes on every change
+static PROBE_CACHE: Lazy<Arc<probes::ProbeCache>> = Lazy::new(|| Arc::new(probes::ProbeCache::new(OPTS.probe_cache_ttl)));
+
+fn main() {
     Builder::new()
         .filter_level(OPTS.log_level)
         .format_timestamp(Some(env_logger::TimestampPrecision::Seconds))
@@ -44,11 +53,48 @@ async fn main() {
     debug!("Running as user: {}", Uid::current().as_raw());
     info!("Starting drophost!");
 
+    if OPTS.list_probes {
+        for probe in probes::ProbeRegistry::new().probes() {
+            println!("{}\t{}", probe.name(), probe.description());
+        }
+        return;
+    }
+
+    if let Some(cli::Command::Test { dir, cases }) = &OPTS.command {
+        test(dir, cases.as_deref());
+        return;
+    }
+
+    if let Some(cli::Command::List) = &OPTS.command {
+        list();
+        return;
+    }
+
+    if let Some(cli::Command::Disable { name }) = &OPTS.command {
+        toggle(name, false);
+        return;
+    }
+
+    if let Some(cli::Command::Enable { name }) = &OPTS.command {
+        toggle(name, true);
+        return;
+    }
+
+    if let Some(cli::Command::Explain { hostname }) = &OPTS.command {
+        explain(hostname);
+        return;
+    }
+
+    if let Some(cli::Command::Query { name, ip, json }) = &OPTS.command {
+        query(name.as_deref(), ip.as_deref(), *json);
+        return;
+    }
+
     if OPTS.backup {
         backup();
     }
 
-    run(!OPTS.check);
+    let next_run = run(!OPTS.check);
 
     if OPTS.daemon && OPTS.watch {
         daemonize(&OPTS.pid_file);
@@ -60,29 +106,92 @@ async fn main() {
     }
 
     if OPTS.watch {
-        watch().await;
+        // Built after forking, as the threads of a runtime don't survive fork()
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("Could not start the runtime");
+        runtime.block_on(watch(next_run));
     }
 }
 
-fn backup() {
-    let root_prefix: &str;
+/// Directory containing the hosts file and the drop-in directory
+fn root() -> PathBuf {
+    match &OPTS.root {
+        Some(root) => root.clone(),
+        None if OPTS.dry_run => PathBuf::from("./output"),
+        None => PathBuf::from("/etc"),
+    }
+}
+
+fn config_dir() -> PathBuf {
+    OPTS.config_dir.clone().unwrap_or_else(|| root().join("hosts.d"))
+}
+
+/// Lower priority drop-in directories. The system ones are found next to the root
+/// if it is an 'etc' directory, as '/run' is next to '/etc', or inside of it otherwise
+fn fallback_dirs() -> Vec<PathBuf> {
+    if !OPTS.fallback_dir.is_empty() {
+        return OPTS.fallback_dir.clone();
+    }
+    let root = root();
+    let system_root = match (root.file_name(), root.parent()) {
+        (Some(name), Some(parent)) if name == "etc" => parent.to_owned(),
+        _ => root,
+    };
+    SYSTEM_FALLBACK_DIRS.iter().map(|dir| system_root.join(dir)).collect()
+}
+
+/// In a dry run, files outside of the root directory are written to it instead
+fn output_file(path: &Path) -> PathBuf {
+    if !OPTS.dry_run {
+        return path.to_owned();
+    }
+    // Files under the root keep their place under ./output, other paths are mapped as if
+    // the root was /etc, so that a dry run never writes to the configured files
+    let relative = path.strip_prefix(root())
+        .or_else(|_| path.strip_prefix("/etc"))
+        .or_else(|_| path.strip_prefix("/"))
+        .unwrap_or(path);
+    Path::new("./output").join(relative)
+}
+
+fn output_path() -> PathBuf {
+    output_file(&OPTS.output.clone().unwrap_or_else(|| root().join("hosts")))
+}
+
+/// System files can only be changed by root, files anywhere else are assumed to be writable
+fn check_root(paths: &[PathBuf]) {
     if OPTS.dry_run {
         info!("Dry run, not replacing system files");
-        root_prefix = "./output";
-    } else {
-        root_prefix = "/etc";
+    }
+    if Uid::current().is_root() {
+        return;
     }
 
-    if !Uid::current().is_root() && !OPTS.dry_run {
-        error!("Must run as root! Use --dry-run to test without root");
+    let system_path = paths.iter().find(|path| {
+        let path = path::absolute(path).unwrap_or(path.to_path_buf());
+        SYSTEM_PATHS.iter().any(|dir| path.starts_with(dir))
+    });
+    if let Some(path) = system_path {
+        error!("Must run as root to write '{}'! Use --dry-run or --root to test without root", path.display());
         std::process::exit(1);
     }
+}
+
+/// Paths of the files written by a run
+fn target_paths() -> Vec<PathBuf> {
+    targets().into_iter().map(|target| target.path).collect()
+}
+
+fn backup() {
+    check_root(&[output_file(&config_dir())]);
 
-    let file = root_prefix.to_owned() + "/hosts";
-    let backup_file = root_prefix.to_owned() + "/hosts.d/10-old-config.conf";
+    let file = OPTS.output.clone().unwrap_or_else(|| root().join("hosts"));
+    let dir = output_file(&config_dir());
+    let backup_file = dir.join("10-old-config.conf");
 
     // Create target directory if it doesn't exist
-    let dir = root_prefix.to_owned() + "/hosts.d";
     let res = std::fs::create_dir_all(&dir);
     let _ = utils::unwrap_result_or_err(res, "Could not create hosts.d directory!", true);
 
@@ -90,33 +199,252 @@ fn backup() {
     let _ = utils::unwrap_result_or_err(res, "Could not backup hosts file!", true);
 }
 
-fn run(write: bool) {
-    let root_prefix: &str;
-    if OPTS.dry_run {
-        info!("Dry run, not replacing system files");
-        root_prefix = "./output";
-    } else {
-        root_prefix = "/etc";
+/// Creates a reader for the drop-in directory, configured from the command line
+fn dir_reader() -> parser::DirReader {
+    let mut dir_reader = parser::DirReader::new(&config_dir());
+    dir_reader.set_pattern(OPTS.pattern.clone());
+    for dir in fallback_dirs() {
+        dir_reader.add_fallback_dir(&dir);
+    }
+    dir_reader.cache = PROBE_CACHE.clone();
+    dir_reader.probe_deadline = OPTS.probe_deadline;
+    dir_reader.assume(assume::Assumptions::new(OPTS.assume.clone()));
+    dir_reader.conflict_policy = OPTS.conflicts;
+    #[cfg(feature = "ping")]
+    dir_reader.register_probe(probes::PingProbe::new(drophost::ping::PingOptions {
+        count: OPTS.ping_count,
+        timeout: OPTS.ping_timeout,
+        ttl: OPTS.ping_ttl,
+    }));
+    dir_reader
+}
+
+/// Files to generate, the hosts file unless targets are given
+fn targets() -> Vec<Target> {
+    if OPTS.target.is_empty() {
+        return vec![Target::new(output_path(), OPTS.format)];
+    }
+
+    OPTS.target.iter()
+        .map(|target| Target { path: output_file(&target.path), ..target.clone() })
+        .collect()
+}
+
+/// Returns the next time at which a time-based condition will change, if any
+fn run(write: bool) -> Option<NaiveDateTime> {
+    if write {
+        check_root(&target_paths());
+    }
+
+    let mut dir_reader = dir_reader();
+    dir_reader.parse_all();
+
+    if OPTS.conflicts == ConflictPolicy::Error && !dir_reader.conflicts.is_empty() {
+        error!("Found {} conflicting entries, not updating the hosts file", dir_reader.conflicts.len());
+        if !OPTS.watch {
+            std::process::exit(1);
+        }
+        return dir_reader.next_boundary;
+    }
+
+    let targets = targets();
+    if !write {
+        for target in &targets {
+            info!("{:?} file would be written to: {}", target.format, target.path.display());
+        }
+        return dir_reader.next_boundary;
+    }
+
+    let mut failed = false;
+    for target in &targets {
+        let output_path = target.path.to_string_lossy();
+        let options = target.output_options(&writer::OutputOptions {
+            format: OPTS.format,
+            provenance: OPTS.provenance,
+            managed_block: OPTS.managed_block,
+            group: OPTS.group,
+            sort: OPTS.sort,
+            split_families: OPTS.split_families,
+            zone: OPTS.zone.clone(),
+        });
+        let hosts = target.filter.apply(&dir_reader.hosts);
+        // Targets keep their directories in the dry run output directory
+        if let (true, Some(parent)) = (OPTS.dry_run, target.path.parent()) {
+            if let Err(e) = std::fs::create_dir_all(parent) {
+                warn!("Could not create '{}': {}", parent.display(), e);
+            }
+        }
+        match writer::write_hosts_to_file(&hosts, &output_path, &options) {
+            Ok(Some(changes)) => {
+                info!("Updated '{}'! ({})", output_path, changes);
+                for entry in &changes.added {
+                    debug!("+ {}", entry);
+                }
+                for entry in &changes.removed {
+                    debug!("- {}", entry);
+                }
+            },
+            Ok(None) => info!("'{}' is already up to date", output_path),
+            Err(e) => {
+                error!("Could not write '{}': {}", output_path, e);
+                failed = true;
+            }
+        }
+    }
+    if failed && !OPTS.watch {
+        std::process::exit(1);
     }
 
-    if !Uid::current().is_root() && !OPTS.dry_run && write {
-        error!("Must run as root! Use --dry-run to test without root");
+    if let Some(boundary) = dir_reader.next_boundary {
+        debug!("Next time window boundary: {}", boundary);
+    }
+    dir_reader.next_boundary
+}
+
+fn test(dir: &str, cases: Option<&str>) {
+    let dir = Path::new(dir);
+    if !dir.is_dir() {
+        error!("Drop-in directory '{}' does not exist!", dir.display());
         std::process::exit(1);
     }
+    let cases_dir = match cases {
+        Some(cases) => Path::new(cases).to_owned(),
+        // Next to the drop-in directory, so the cases of each directory stay with it
+        None => dir.join("../hosts.tests"),
+    };
+
+    let cases = match testing::find_cases(&cases_dir) {
+        Ok(cases) => cases,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut failed = 0;
+    for path in &cases {
+        let name = path.file_name().unwrap().to_string_lossy();
+        let res = testing::TestCase::load(path).and_then(|case| case.run(&name, dir));
+        match res {
+            Ok(res) if res.passed => println!("PASS {}", res.name),
+            Ok(res) => {
+                failed += 1;
+                println!("FAIL {}", res.name);
+                for line in res.diff {
+                    println!("  {}", line);
+                }
+            },
+            Err(e) => {
+                failed += 1;
+                println!("ERROR {}: {}", name, e);
+            }
+        }
+    }
 
-    let dir = root_prefix.to_owned() + "/hosts.d";
+    println!("{} passed, {} failed", cases.len() - failed, failed);
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
 
-    let mut dir_reader = parser::DirReader::new(&path::Path::new(&dir));
+fn list() {
+    let mut dir_reader = dir_reader();
+    dir_reader.parse_all();
 
+    println!("STATE\tPRIORITY\tENTRIES\tFILE");
+    for file in &dir_reader.files {
+        let priority = &dir_reader.priorities[file];
+        if parser::is_masked(file) {
+            println!("masked\t{}\t-\t{}", priority, file.display());
+            continue;
+        }
+        let entries = dir_reader.hosts.hosts.iter()
+            .filter(|host| host.source.as_ref().is_some_and(|source| source.path == *file))
+            .count();
+        println!("active\t{}\t{}\t{}", priority, entries, file.display());
+    }
+    for file in &dir_reader.disabled {
+        println!("disabled\t-\t-\t{}", file.display());
+    }
+}
+
+fn toggle(name: &str, enable: bool) {
+    if OPTS.dry_run {
+        info!("Dry run, not {} '{}'", if enable { "enabling" } else { "disabling" }, name);
+        run(!OPTS.check);
+        return;
+    }
+    check_root(&[config_dir()]);
+
+    let mut dir_reader = dir_reader();
+    let res = if enable { dir_reader.enable(name) } else { dir_reader.disable(name) };
+    match res {
+        Ok(path) if enable => info!("Enabled '{}'", path.display()),
+        Ok(path) => info!("Disabled '{}' with '{}'", name, path.display()),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    run(!OPTS.check);
+}
+
+fn explain(hostname: &str) {
+    let mut dir_reader = dir_reader();
+    dir_reader.explain = Some(hostname.to_owned());
     dir_reader.parse_all();
 
-    if write {
-        let output_path = root_prefix.to_owned() + "/hosts";
+    if dir_reader.trace.is_empty() {
+        println!("No line mentions '{}'", hostname);
+    }
+    for entry in &dir_reader.trace {
+        print!("{}", entry);
+    }
+
+    for conflict in dir_reader.conflicts.iter().filter(|c| c.first.hostname == hostname) {
+        println!("Conflict: {}", conflict);
+    }
+
+    let entries = dir_reader.hosts.hosts.iter()
+        .filter(|host| host.hostname == hostname)
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        println!("Result: no entry for '{}'", hostname);
+    }
+    for host in entries {
+        match &host.source {
+            Some(source) => println!("Result: {} (from {})", host, source),
+            None => println!("Result: {}", host),
+        }
+    }
+}
+
+fn query(name: Option<&str>, ip: Option<&str>, json: bool) {
+    let mut dir_reader = dir_reader();
+    dir_reader.parse_all();
+
+    let entries = match (name, ip) {
+        (_, Some(ip)) => dir_reader.hosts.find_ip(ip).collect::<Vec<_>>(),
+        (Some(name), None) => dir_reader.hosts.find_hostname(name).collect::<Vec<_>>(),
+        (None, None) => unreachable!("clap requires a name or an IP"),
+    };
 
-        writer::write_hosts_to_file(&dir_reader.hosts, &output_path);
-        info!("Updated hosts file!")
+    if json {
+        let res = serde_json::to_string_pretty(&entries);
+        println!("{}", utils::unwrap_result_or_err(res, "Could not serialize entries!", true).unwrap());
     } else {
-        info!("Hosts file would be written to: {}", root_prefix.to_owned() + "/hosts");
+        for host in &entries {
+            match &host.source {
+                Some(source) => println!("{}\t# from {}", host, source),
+                None => println!("{}", host),
+            }
+        }
+    }
+
+    // Like getent, scripts can tell whether anything was found from the exit status
+    if entries.is_empty() {
+        std::process::exit(2);
     }
 }
 
@@ -130,11 +458,10 @@ fn daemonize(pidfile: &str) {
             std::process::exit(0);
         },
         Ok(ForkResult::Child) => {
+            // No runtime was started yet, probes run on one of their own
             info!("Running as daemon");
-            let _ = run(!OPTS.check);
-            if OPTS.watch {
-                watch();
-            }
+            // main() picks up the watch loop once we return
+            run(!OPTS.check);
         },
         Err(e) => {
             error!("Could not fork to background: {}", e);
@@ -143,41 +470,69 @@ fn daemonize(pidfile: &str) {
     }
 }
 
-async fn watch() {
-    let root_prefix: &str;
-    if OPTS.dry_run {
-        info!("Dry run, not replacing system files");
-        root_prefix = "./output";
-    } else {
-        root_prefix = "/etc";
-    }
-
-    if !Uid::current().is_root() && !OPTS.dry_run {
-        error!("Must run as root! Use --dry-run to test without root");
-        std::process::exit(1);
-    }
+async fn watch(mut next_run: Option<NaiveDateTime>) {
+    check_root(&target_paths());
 
-    let dir = root_prefix.to_owned() + "/hosts.d";
-
-    let path = Path::new(&dir);
+    let dir = config_dir();
+    let path = dir.as_path();
 
     info!("Watching directory: {}", path.display());
-    let mut watcher = recommended_watcher(handler).unwrap();
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = recommended_watcher(tx).unwrap();
 
-    watcher.watch(&path, RecursiveMode::Recursive).unwrap();
+    watcher.watch(path, RecursiveMode::Recursive).unwrap();
+    for dir in fallback_dirs().iter().filter(|dir| dir.is_dir()) {
+        info!("Watching directory: {}", dir.display());
+        watcher.watch(dir, RecursiveMode::Recursive).unwrap();
+    }
 
+    let clock = clock::SystemClock;
     loop {
-        std::thread::park();
+        // Wake up either on a filesystem event or when a time window opens or closes
+        let res = match next_run {
+            Some(boundary) => {
+                let wait = (boundary - clock.now()).to_std().unwrap_or_default();
+                match rx.recv_timeout(wait) {
+                    Ok(res) => Some(res),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            },
+            None => match rx.recv() {
+                Ok(res) => Some(res),
+                Err(_) => break,
+            },
+        };
+
+        next_run = match res {
+            Some(res) => handler(res, next_run),
+            None => {
+                info!("Time window boundary reached, re-running drophost's parser");
+                run(!OPTS.check)
+            },
+        };
     }
 }
 
-fn handler(res: notify::Result<notify::Event>) {
+fn handler(res: notify::Result<notify::Event>, next_run: Option<NaiveDateTime>) -> Option<NaiveDateTime> {
     match res {
         Ok(event) => {
             debug!("Event: {:?}", event);
+            // Every run opens the files it reads, which must not trigger the next one
+            if let notify::EventKind::Access(_) = event.kind {
+                return next_run;
+            }
+            // Changes to editor swap files and the like can't change the result
+            if !event.paths.iter().any(|path| path.is_dir() || parser::is_config_file(path, &OPTS.pattern)) {
+                debug!("Ignoring changes to files that aren't read");
+                return next_run;
+            }
             info!("Change detected, re-running drophost's parser");
-            let _ = run(!OPTS.check);
+            run(!OPTS.check)
+        },
+        Err(e) => {
+            error!("An error has occured while watching: {:?}", e);
+            next_run
         },
-        Err(e) => error!("An error has occured while watching: {:?}", e),
     }
 }
\ No newline at end of file
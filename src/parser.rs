@@ -10,24 +10,211 @@ use std::path::{Path, PathBuf};
 use std::boxed::Box;
 use std::str::{FromStr};
 use std::collections::VecDeque;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
 
 #[cfg(any(feature = "interface", feature = "range"))]
 use network_interface::{NetworkInterface, NetworkInterfaceConfig};
 
-use crate::types::{Hosts, Host};
+use crate::types::{Hosts, Host, Removal, Source};
+use crate::clock::{earliest, Clock, SystemClock};
+use crate::probes::{self, Probe, ProbeCache, ProbeContext, ProbeJob, ProbeKey, ProbeOutcome, ProbeRegistry};
+use crate::facts::{FactsProvider, SystemFacts};
+use crate::assume::Assumptions;
+use crate::conflicts::{self, Conflict, ConflictPolicy};
+use crate::explain::{Decision, Outcome, TraceEntry};
+use crate::writer::{BEGIN_MARKER, END_MARKER};
+
+use glob::Pattern;
+
+/// Files read from drop-in directories unless another pattern is set
+pub const DEFAULT_PATTERN: &str = "*.conf";
+
+// Suffixes of files left behind by editors and package managers
+const IGNORED_SUFFIXES: [&str; 20] = [
+    "~", ".swp", ".swo", ".bak", ".orig", ".tmp",
+    ".dpkg-old", ".dpkg-new", ".dpkg-dist", ".dpkg-bak", ".dpkg-tmp",
+    ".rpmnew", ".rpmsave", ".rpmorig",
+    ".pacnew", ".pacsave", ".pacorig",
+    ".ucf-old", ".ucf-new", ".ucf-dist",
+];
+
+/// Whether the file or directory is hidden or was left behind by an editor or a package manager
+pub fn is_junk(path: &Path) -> bool {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return false,
+    };
+    name.starts_with('.')
+        || (name.starts_with('#') && name.ends_with('#'))
+        || IGNORED_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+}
+
+/// Suffix added to the name of a drop-in file by 'drophost disable'
+pub const DISABLED_SUFFIX: &str = ".disabled";
+
+/// Whether the file was disabled, see `DirReader::disable`
+pub fn is_disabled(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name.to_string_lossy().ends_with(DISABLED_SUFFIX))
+}
+
+/// Whether the file is one that should be read from a drop-in directory
+pub fn is_config_file(path: &Path, pattern: &Pattern) -> bool {
+    !is_junk(path) && !is_disabled(path)
+        && path.file_name().is_some_and(|name| pattern.matches(&name.to_string_lossy()))
+}
+
+// The path the file had before it was disabled
+fn strip_disabled_suffix(path: &Path) -> PathBuf {
+    let name = path.file_name().expect("This should never happen! (strip_disabled_suffix)").to_string_lossy();
+    path.with_file_name(name.strip_suffix(DISABLED_SUFFIX).unwrap_or(&name))
+}
+
+/// Where the position of a file in the reading order comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// A 'priority' line at the top of the file
+    Directive(i64),
+    /// The number the name of the file starts with
+    Name(i64),
+    /// Files without a priority are read after every other one
+    None,
+}
+
+impl Priority {
+    /// Reads the 'priority' directive of the file, if any, or takes it from its name
+    pub fn of(path: &Path) -> Self {
+        if let Some(priority) = read_priority_directive(path) {
+            return Priority::Directive(priority);
+        }
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        Priority::of_name(&name)
+    }
+
+    /// Priority of a file or directory without a directive, the number its name starts with
+    pub fn of_name(name: &str) -> Self {
+        let digits = name.chars().take_while(|c| c.is_ascii_digit()).collect::<String>();
+        match digits.parse() {
+            Ok(priority) => Priority::Name(priority),
+            Err(_) => Priority::None,
+        }
+    }
+
+    pub fn value(&self) -> Option<i64> {
+        match self {
+            Priority::Directive(priority) | Priority::Name(priority) => Some(*priority),
+            Priority::None => None,
+        }
+    }
+
+    // Files without a priority go last
+    fn order(&self, other: &Self) -> std::cmp::Ordering {
+        match (self.value(), other.value()) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (a, b) => b.is_some().cmp(&a.is_some()),
+        }
+    }
+}
+
+impl Display for Priority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Priority::Directive(priority) => write!(f, "{} (directive)", priority),
+            Priority::Name(priority) => write!(f, "{}", priority),
+            Priority::None => write!(f, "-"),
+        }
+    }
+}
+
+/// The value of a 'priority' line, `None` if the line is another statement
+pub fn parse_priority(line: &str) -> Option<Result<i64, String>> {
+    let mut split = line.split_whitespace();
+    if split.next() != Some("priority") {
+        return None;
+    }
+    match (split.next(), split.next()) {
+        (Some(priority), None) => Some(priority.parse().map_err(|_| format!("Invalid priority '{}'", priority))),
+        _ => Some(Err("'priority' needs a single number".to_string())),
+    }
+}
+
+/// The value of the 'priority' line in the header of the file, before any statement or entry.
+/// Only the header is read, invalid lines are reported when the file is parsed.
+pub fn read_priority_directive(path: &Path) -> Option<i64> {
+    let file = File::open(path).ok()?;
+    let line = BufReader::new(file).lines()
+        .map_while(Result::ok)
+        .find(|line| !line.trim().is_empty() && !line.trim().starts_with('#'))?;
+    parse_priority(&line)?.ok()
+}
+
+/// Whether the file is a link to '/dev/null', which hides files with the same name
+pub fn is_masked(path: &Path) -> bool {
+    path.is_symlink() && fs::canonicalize(path).is_ok_and(|target| target == Path::new("/dev/null"))
+}
 
 pub struct DirReader {
     pub dir_path: Box<PathBuf>,
+    // Lower priority directories, see 'add_fallback_dir'
+    pub fallback_dirs: Vec<PathBuf>,
+    // Only files whose name matches are read
+    pub pattern: Pattern,
+    // Why each file is read at its position, see 'Priority'
+    pub priorities: HashMap<PathBuf, Priority>,
     pub hosts: Box<Hosts>,
 
     pub vars: HashMap<String, String>,
 
+    // Files to read, 'priorities' and 'disabled' are only filled once 'scan' was called
     pub files: Vec<PathBuf>,
     pub file_index: usize,
+    // Files that would be read if they were not disabled
+    pub disabled: Vec<PathBuf>,
+    // Whether the files were listed since the directories or the pattern last changed
+    pub scanned: bool,
+
+    pub clock: Arc<dyn Clock>,
+    pub probes: Arc<ProbeRegistry>,
+    pub cache: Arc<ProbeCache>,
+    pub prefetched: Arc<HashMap<ProbeKey, ProbeOutcome>>,
+    pub assumptions: Arc<Assumptions>,
+    // How long prefetched probes may take in total
+    pub probe_deadline: Duration,
+    pub conflict_policy: ConflictPolicy,
+    // Conflicts found by the last call to 'parse_all'
+    pub conflicts: Vec<Conflict>,
+    // Hostname to record a trace for, see 'explain'
+    pub explain: Option<String>,
+    pub trace: Vec<TraceEntry>,
+    // Earliest time at which a 'try time' or 'try day' condition will flip
+    pub next_boundary: Option<NaiveDateTime>,
 }
 
 impl DirReader {
     pub fn new(dir_path: &Path) -> Self {
+        Self::with_facts(dir_path, &SystemFacts)
+    }
+
+    /// Same as `new`, but takes the `sys_*` variables from the given provider
+    pub fn with_facts(dir_path: &Path, facts: &dyn FactsProvider) -> Self {
+        // Add the system facts to the vars map with the name 'sys_FACT'
+        let mut vars = facts.vars();
+
+        // Add all of the user's environment variables to the vars map with the name 'env_VARNAME'
+        for (key, value) in std::env::vars() {
+            let key = format!("env_{}", key);
+            let value = value.to_owned();
+            vars.insert(key, value);
+        }
+
+        Self::with_vars(dir_path, vars)
+    }
+
+    /// Starts with exactly the given variables, without any `sys_*` or `env_*` one
+    pub fn with_vars(dir_path: &Path, vars: HashMap<String, String>) -> Self {
         // Check if the directory exists
         if !dir_path.exists() {
             info!("Config directory does not exist, creating...");
@@ -50,60 +237,328 @@ impl DirReader {
             std::process::exit(1);
         }
 
-        // Read the directory
+        DirReader {
+            dir_path: Box::new(dir_path.to_owned()),
+            fallback_dirs: vec![],
+            pattern: Pattern::new(DEFAULT_PATTERN).expect("This should never happen! (DirReader::with_vars)"),
+            priorities: HashMap::new(),
+            hosts: Box::new(Hosts::new()),
+            files: vec![],
+            file_index: 0,
+            disabled: vec![],
+            scanned: false,
+            vars,
+            clock: Arc::new(SystemClock),
+            probes: Arc::new(ProbeRegistry::new()),
+            cache: Arc::new(ProbeCache::default()),
+            prefetched: Arc::new(HashMap::new()),
+            assumptions: Arc::new(Assumptions::default()),
+            probe_deadline: probes::DEFAULT_PROBE_DEADLINE,
+            conflict_policy: ConflictPolicy::default(),
+            conflicts: vec![],
+            explain: None,
+            trace: vec![],
+            next_boundary: None,
+        }
+    }
+
+    // Files of the directory and of its subdirectories matching the pattern, including
+    // the disabled ones
+    fn read_dir(&self, dir_path: &Path) -> Vec<PathBuf> {
         let dir_read_res = fs::read_dir(dir_path);
         let dir_read = unwrap_result_or_err(dir_read_res,
                                         "Failed to read directory!",
-                                        true).expect("This should never happen! (DirReader::new)");
+                                        true).expect("This should never happen! (DirReader::read_dir)");
+
+        let mut files = vec![];
+        for path in dir_read.map(|res| res.unwrap().path()) {
+            if path.is_dir() {
+                if is_junk(&path) || path.is_symlink() {
+                    // Links to directories could make us go around in circles
+                    debug!("Skipping directory '{}'", path.to_str().unwrap());
+                    continue;
+                }
+                files.extend(self.read_dir(&path));
+            } else if is_disabled(&path) && is_config_file(&strip_disabled_suffix(&path), &self.pattern) {
+                files.push(path);
+            } else if !is_config_file(&path, &self.pattern) {
+                debug!("Skipping '{}', it is not a configuration file", path.to_str().unwrap());
+            } else {
+                files.push(path);
+            }
+        }
+        files
+    }
 
-        // Sort the files by name alphabetically
-        let mut dir_read: Vec<_> = dir_read.collect();
-        dir_read.sort_by_key(|entry| entry.as_ref().unwrap().path());
+    /// Lists the files of every directory, unless it was already done since the directories
+    /// or the pattern changed. Called before the files are read or looked up.
+    pub fn scan(&mut self) {
+        if !self.scanned {
+            self.rescan();
+        }
+    }
 
-        
-        let files = dir_read.iter().map(|res| res.as_ref().unwrap().path())
-                            .collect::<Vec<_>>();
+    // Lists the files of every directory, from the highest priority one. Files are
+    // identified by their path relative to their directory. At each level of that path,
+    // files and subdirectories are sorted by priority and then naturally by name, so
+    // that a subdirectory is read as a whole, whatever the priorities of its files.
+    fn rescan(&mut self) {
+        let mut files: Vec<(PathBuf, PathBuf)> = vec![];
+        self.disabled.clear();
+        for dir_path in std::iter::once(self.dir_path.as_ref()).chain(&self.fallback_dirs) {
+            if !dir_path.is_dir() {
+                debug!("Fallback directory '{}' does not exist, skipping", dir_path.to_str().unwrap());
+                continue;
+            }
 
-        // Add all of the user's environment variables to the vars map with the name 'env_VARNAME'
-        let mut vars = HashMap::new();
-        for (key, value) in std::env::vars() {
-            let key = format!("env_{}", key);
-            let value = value.to_owned();
-            vars.insert(key, value);
+            for file in self.read_dir(dir_path) {
+                if is_disabled(&file) {
+                    // Lets the files it overrides be read again
+                    debug!("Skipping disabled file '{}'", file.to_str().unwrap());
+                    self.disabled.push(file);
+                    continue;
+                }
+                let relative = file.strip_prefix(dir_path).expect("This should never happen! (DirReader::scan)").to_owned();
+                match files.iter().find(|(other, _)| *other == relative) {
+                    Some((_, overriding)) => debug!("'{}' is overridden by '{}'", file.to_str().unwrap(), overriding.to_str().unwrap()),
+                    None => files.push((relative, file)),
+                }
+            }
+        }
+        self.priorities = files.iter()
+            .map(|(_, file)| (file.clone(), Priority::of(file)))
+            .collect();
+        let mut keyed = files.into_iter()
+            .map(|(relative, file)| {
+                let mut key = relative.components()
+                    .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                    .map(|name| (Priority::of_name(&name), name))
+                    .collect::<Vec<_>>();
+                if let Some((priority, _)) = key.last_mut() {
+                    *priority = self.priorities[&file];
+                }
+                (key, file)
+            })
+            .collect::<Vec<_>>();
+        keyed.sort_by(|(a, _), (b, _)| a.iter().zip(b)
+            .map(|((a_priority, a_name), (b_priority, b_name))| a_priority.order(b_priority).then_with(|| natural_cmp(a_name, b_name)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())));
+        self.files = keyed.into_iter().map(|(_, file)| file).collect();
+        self.disabled.sort_by(|a, b| natural_path_cmp(a, b));
+        self.scanned = true;
+    }
+
+    /// Path of the file relative to the drop-in directory it was found in
+    pub fn relative_path<'a>(&self, file: &'a Path) -> Option<&'a Path> {
+        std::iter::once(self.dir_path.as_ref()).chain(&self.fallback_dirs)
+            .find_map(|dir_path| file.strip_prefix(dir_path).ok())
+    }
+
+    // The only file whose relative path, with or without its extension, or whose name is `name`
+    fn find_file<'a>(&self, name: &str, files: impl Iterator<Item = &'a PathBuf>) -> Result<Option<&'a PathBuf>, String> {
+        let found = files
+            .filter(|file| {
+                let path = strip_disabled_suffix(file);
+                let relative = self.relative_path(&path).unwrap_or(&path);
+                relative == Path::new(name)
+                    || relative.with_extension("") == Path::new(name)
+                    || relative.file_name().is_some_and(|file_name| file_name == name)
+            })
+            .collect::<Vec<_>>();
+        match found.as_slice() {
+            [] => Ok(None),
+            [file] => Ok(Some(file)),
+            _ => Err(format!("'{}' matches several files: {}", name,
+                             found.iter().map(|file| format!("'{}'", file.to_str().unwrap())).collect::<Vec<_>>().join(", "))),
         }
+    }
 
-        DirReader {
-            dir_path: Box::new(dir_path.to_owned()),
-            hosts: Box::new(Hosts::new()),
-            files,
-            file_index: 0,
-            vars,
+    /// Stops reading the file named `name`, see `find_file`. Files of the drop-in
+    /// directory get the '.disabled' suffix, files of fallback directories belong to
+    /// packages and are masked from the drop-in directory instead. If the file overrode
+    /// one of a fallback directory, that one is read again.
+    /// Returns the path of the disabled or masking file.
+    pub fn disable(&mut self, name: &str) -> Result<PathBuf, String> {
+        self.scan();
+        let file = self.find_file(name, self.files.iter().filter(|file| !is_masked(file)))?
+            .ok_or_else(|| format!("No active file matches '{}'", name))?
+            .clone();
+        let relative = self.relative_path(&file).expect("This should never happen! (DirReader::disable)").to_owned();
+        let disabled = if file.starts_with(self.dir_path.as_ref()) {
+            let mut disabled = file.clone().into_os_string();
+            disabled.push(DISABLED_SUFFIX);
+            let disabled = PathBuf::from(disabled);
+            fs::rename(&file, &disabled)
+                .map_err(|e| format!("Could not rename '{}': {}", file.to_str().unwrap(), e))?;
+            disabled
+        } else {
+            let mask = self.dir_path.join(&relative);
+            if let Some(parent) = mask.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("Could not create directory '{}': {}", parent.to_str().unwrap(), e))?;
+            }
+            std::os::unix::fs::symlink("/dev/null", &mask)
+                .map_err(|e| format!("Could not mask '{}': {}", file.to_str().unwrap(), e))?;
+            mask
+        };
+        self.rescan();
+        // A file of a fallback directory that was overridden is read again
+        if let Some(fallback) = self.files.iter().find(|other| !is_masked(other) && self.relative_path(other) == Some(&relative)) {
+            info!("'{}' is read again instead of '{}'", fallback.to_str().unwrap(), file.to_str().unwrap());
         }
+        Ok(disabled)
+    }
+
+    /// Reads the file named `name` again, by removing its '.disabled' suffix or the link
+    /// to '/dev/null' masking it. Returns the path of the enabled file.
+    pub fn enable(&mut self, name: &str) -> Result<PathBuf, String> {
+        self.scan();
+        let enabled = if let Some(file) = self.find_file(name, self.disabled.iter())? {
+            let enabled = strip_disabled_suffix(file);
+            if enabled.exists() {
+                return Err(format!("Could not enable '{}': '{}' already exists", file.to_str().unwrap(), enabled.to_str().unwrap()));
+            }
+            fs::rename(file, &enabled)
+                .map_err(|e| format!("Could not rename '{}': {}", file.to_str().unwrap(), e))?;
+            enabled
+        } else if let Some(mask) = self.find_file(name, self.files.iter().filter(|file| is_masked(file)))? {
+            let mask = mask.clone();
+            fs::remove_file(&mask)
+                .map_err(|e| format!("Could not remove '{}': {}", mask.to_str().unwrap(), e))?;
+            let relative = self.relative_path(&mask).expect("This should never happen! (DirReader::enable)").to_owned();
+            self.rescan();
+            return self.files.iter().find(|file| self.relative_path(file) == Some(&relative)).cloned()
+                .ok_or_else(|| format!("No file was masked by '{}'", mask.to_str().unwrap()));
+        } else {
+            return Err(format!("No disabled file matches '{}'", name));
+        };
+        self.rescan();
+        Ok(enabled)
+    }
+
+    /// Adds the files of a lower priority directory, such as '/usr/lib/hosts.d'. Like
+    /// systemd drop-ins, a file is only read if no directory added before has a file with
+    /// the same relative path, so a file linked to '/dev/null' masks the ones at its path.
+    /// Files from every directory are read in the natural order of their relative paths.
+    pub fn add_fallback_dir(&mut self, dir_path: &Path) {
+        self.fallback_dirs.push(dir_path.to_owned());
+        self.scanned = false;
+    }
+
+    /// Only reads the files whose name matches the pattern, `DEFAULT_PATTERN` by default.
+    /// Hidden files and files left behind by editors and package managers are never read.
+    pub fn set_pattern(&mut self, pattern: Pattern) {
+        self.pattern = pattern;
+        self.scanned = false;
     }
 
+    /// Replaces probe results and variables with the given ones for every file.
+    /// Assumed variables can't be changed by 'set' or 'unset'.
+    pub fn assume(&mut self, assumptions: Assumptions) {
+        self.vars.extend(assumptions.vars().clone());
+        self.assumptions = Arc::new(assumptions);
+    }
+
+    /// Makes a custom probe available to 'try' statements in every file
+    pub fn register_probe<P: Probe + 'static>(&mut self, probe: P) {
+        Arc::make_mut(&mut self.probes).register(probe);
+    }
+
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Option<FileReader> {
+        self.scan();
         if self.file_index >= self.files.len() {
             info!("No more files to parse!");
             return None;
         }
 
         let file = &self.files[self.file_index];
-        info!("Parsing file '{}'...", file.to_str().unwrap());
         self.file_index += 1;
+        if is_masked(file) {
+            debug!("Skipping masked file '{}'", file.to_str().unwrap());
+            return self.next();
+        }
+        info!("Parsing file '{}'...", file.to_str().unwrap());
 
-        Some(FileReader::new(file, self.vars.clone()))
+        let mut reader = FileReader::new(file, self.vars.clone());
+        reader.clock = self.clock.clone();
+        reader.probes = self.probes.clone();
+        reader.cache = self.cache.clone();
+        reader.prefetched = self.prefetched.clone();
+        reader.assumptions = self.assumptions.clone();
+        reader.explain = self.explain.clone();
+        Some(reader)
     }
 
     pub fn get_hosts(&self) -> &Hosts {
         &self.hosts
     }
 
+    /// Runs every probe that can be checked ahead of evaluation concurrently,
+    /// so that slow network checks don't add up while parsing. This includes
+    /// probes in branches that end up not being taken.
+    pub fn prefetch(&mut self) {
+        self.scan();
+        let mut jobs: HashMap<ProbeKey, ProbeJob> = HashMap::new();
+        for file in &self.files[self.file_index..] {
+            // Errors are reported when the file is actually parsed
+            let contents = match fs::read_to_string(file) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+
+            for (index, line) in contents.lines().enumerate() {
+                let statement = match line.trim().strip_prefix("try ").map(TryStatement::parse) {
+                    Some(Ok(statement)) => statement,
+                    _ => continue,
+                };
+                if self.assumptions.probe(statement.probe, statement.argument).is_some() {
+                    continue;
+                }
+                let probe = match self.probes.get_shared(statement.probe) {
+                    Some(probe) if probe.prefetch() => probe,
+                    _ => continue,
+                };
+                if let Ok(Some(ttl)) = statement.ttl(probe.as_ref(), self.cache.default_ttl) {
+                    if self.cache.get(statement.probe, statement.argument, ttl).is_some() {
+                        continue;
+                    }
+                }
+
+                let key = (statement.probe.to_owned(), statement.argument.to_owned());
+                jobs.entry(key).or_insert(ProbeJob {
+                    probe,
+                    argument: statement.argument.to_owned(),
+                    path: file.clone(),
+                    line: index + 1,
+                });
+            }
+        }
+
+        if jobs.is_empty() {
+            return;
+        }
+        debug!("Running {} probe(s) ahead of evaluation", jobs.len());
+        let results = probes::run_concurrently(jobs.into_values().collect(), self.clock.clone(), self.probe_deadline);
+        self.prefetched = Arc::new(results);
+    }
+
     pub fn parse_all (&mut self) {
+        self.prefetch();
         while let Some(mut file) = self.next() {
             file.parse_all();
+            // Removals were already applied to the file's own hosts, they
+            // still need to be applied to the ones from earlier files
+            for removal in &file.removals {
+                self.hosts.apply_removal(removal);
+            }
             self.hosts.extend(&file.hosts);
+            self.trace.extend(file.trace);
             self.vars.extend(file.vars);
+            self.next_boundary = earliest(self.next_boundary, file.next_boundary);
         }
+        self.conflicts = conflicts::resolve(&mut self.hosts, self.conflict_policy);
     }
 }
 
@@ -123,8 +578,28 @@ pub struct FileReader {
     // Redundant, but useful for error messages
     pub line_index: usize,
 
+    // Inside a block written by drophost in managed block mode, see 'writer::splice_managed_block'
+    pub in_managed_block: bool,
+
     pub vars: HashMap<String, String>,
     pub cond_stack: VecDeque<bool>,
+
+    // 'remove' and 'override' statements, in order
+    pub removals: Vec<Removal>,
+
+    // Hostname to record a trace for, along with the enclosing decisions
+    // and the details noted while parsing the current line
+    pub explain: Option<String>,
+    pub trace: Vec<TraceEntry>,
+    pub decisions: Vec<Decision>,
+    pub details: Vec<String>,
+
+    pub clock: Arc<dyn Clock>,
+    pub probes: Arc<ProbeRegistry>,
+    pub cache: Arc<ProbeCache>,
+    pub prefetched: Arc<HashMap<ProbeKey, ProbeOutcome>>,
+    pub assumptions: Arc<Assumptions>,
+    pub next_boundary: Option<NaiveDateTime>,
 }
 
 impl FileReader {
@@ -153,8 +628,20 @@ impl FileReader {
             parse_state: ParseState::Waiting,
             lines,
             line_index: 0,
+            in_managed_block: false,
             vars,
             cond_stack: stack,
+            removals: vec![],
+            explain: None,
+            trace: vec![],
+            decisions: vec![],
+            details: vec![],
+            clock: Arc::new(SystemClock),
+            probes: Arc::new(ProbeRegistry::new()),
+            cache: Arc::new(ProbeCache::default()),
+            prefetched: Arc::new(HashMap::new()),
+            assumptions: Arc::new(Assumptions::default()),
+            next_boundary: None,
         }
     }
 
@@ -175,31 +662,83 @@ impl FileReader {
             let cond = line.trim_start_matches("if ");
             let cond = self.parse_conditional(cond);
             self.cond_stack.push_back(cond);
+            self.push_decision(&line, cond, true);
             return true;
         }
         if line.starts_with("try ") {
             let attempt = line.trim_start_matches("try ");
             let attempt = self.parse_try(attempt);
             self.cond_stack.push_back(attempt);
+            self.push_decision(&line, attempt, true);
             self.parse_state = ParseState::Conditional;
             return true;
         }
 
+        if let Some(priority) = parse_priority(&line) {
+            // Read when listing the files, see 'Priority::of'
+            let header = self.lines[..self.line_index - 1].iter()
+                .all(|line| line.trim().is_empty() || line.trim().starts_with('#'));
+            if !header {
+                warn!("Syntax error in file {} at line {}: 'priority' must come before any other statement", self.path.to_string_lossy(), self.line_index);
+                return false;
+            }
+            if let Err(e) = priority {
+                warn!("Syntax error in file {} at line {}: {}", self.path.to_string_lossy(), self.line_index, e);
+                return false;
+            }
+            return true;
+        }
+
         if line.starts_with("set ") {
             let set = line.trim_start_matches("set ");
             let mut split = set.splitn(2, '=');
             let key = split.next().unwrap().trim();
             let value = split.next().unwrap().trim();
+            if self.assumptions.var(key).is_some() {
+                debug!("Ignoring 'set {}' in file '{}' at line {}, the variable is assumed", key, self.path.to_str().unwrap(), self.line_index);
+                return true;
+            }
             self.vars.insert(key.to_owned(), value.to_owned());
             return true;
         }
 
         if line.starts_with("unset ") {
             let unset = line.trim_start_matches("unset ");
+            if self.assumptions.var(unset).is_some() {
+                debug!("Ignoring 'unset {}' in file '{}' at line {}, the variable is assumed", unset, self.path.to_str().unwrap(), self.line_index);
+                return true;
+            }
             self.vars.remove(unset);
             return true;
         }
 
+        if line.starts_with("remove ") {
+            let target = line.trim_start_matches("remove ").trim();
+            let removal = match target.strip_prefix("ip ") {
+                Some(ip) => Removal::Ip(self.parse_var_or_literal(ip.trim())),
+                None => Removal::Hostname(self.parse_var_or_literal(target)),
+            };
+            self.remove(removal);
+            return true;
+        }
+
+        if line.starts_with("override ") {
+            let mut split = line.trim_start_matches("override ").split_whitespace();
+            let (ip, hostname) = match (split.next(), split.next()) {
+                (Some(ip), Some(hostname)) => (ip, hostname),
+                _ => {
+                    warn!("Syntax error in file {} at line {}: 'override' needs an IP and a hostname", self.path.to_string_lossy(), self.line_index);
+                    return false;
+                }
+            };
+            let ip = self.parse_var_or_literal(ip);
+            let hostname = self.parse_var_or_literal(hostname);
+            self.remove(Removal::Hostname(hostname.clone()));
+            let source = self.source();
+            self.hosts.add(Host::new(hostname, ip).with_source(source));
+            return true;
+        }
+
         if line.is_empty() {
             return true;
         }
@@ -223,14 +762,38 @@ impl FileReader {
         let hostname = self.parse_var_or_literal(hostname.unwrap());
         let ip = self.parse_var_or_literal(ip.unwrap());
 
-        let host = Host::new(hostname.to_string(), ip.to_string());
+        // Tags, as in '@lan', anything else after the hostname is ignored
+        let tags = split
+            .take_while(|token| !token.starts_with('#'))
+            .filter_map(|token| token.strip_prefix('@'))
+            .map(|tag| tag.to_owned())
+            .collect::<Vec<_>>();
+
+        let host = Host::new(hostname, ip.to_string()).with_source(self.source());
         self.hosts.add(host);
-        return true;
+        self.hosts.tag(self.source(), tags);
+        true
+    }
+
+    /// Location of the line currently being parsed
+    fn source(&self) -> Source {
+        Source {
+            path: self.path.as_ref().clone(),
+            line: self.line_index,
+        }
+    }
+
+    /// Removes matching hosts from this file and, once the file is done, from earlier files
+    fn remove(&mut self, removal: Removal) {
+        let removed = self.hosts.apply_removal(&removal);
+        debug!("{:?} in file '{}' at line {} removed {} entries from this file", removal, self.path.to_str().unwrap(), self.line_index, removed);
+        self.removals.push(removal);
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> bool {
         self.line_index += 1;
-        return self.parse_current_line();
+        self.parse_current_line()
     }
 
     pub fn parse_current_line(&mut self) -> bool {
@@ -240,48 +803,164 @@ impl FileReader {
 
         let line = &self.lines[self.line_index - 1].clone();
         let line = line.trim().to_owned();
+
+        // Entries generated by drophost are skipped, so that a backup of a hosts
+        // file in managed block mode doesn't feed them back into the configuration
+        if line == BEGIN_MARKER {
+            if !self.lines[self.line_index..].iter().any(|l| l.trim() == END_MARKER) {
+                warn!("Error while reading file '{}' at line {}: '{}' without '{}', the block is read as usual!",
+                    self.path.to_str().unwrap(), self.line_index, BEGIN_MARKER, END_MARKER);
+                return true;
+            }
+            debug!("Skipping the block generated by drophost in '{}' at line {}", self.path.to_str().unwrap(), self.line_index);
+            self.in_managed_block = true;
+            return true;
+        }
+        if self.in_managed_block {
+            self.in_managed_block = line != END_MARKER;
+            return true;
+        }
+
+        let traced = self.mentions_explained(&line);
+        self.details.clear();
         match self.parse_state {
             ParseState::Normal => {
-                return self.parse();
+                let res = self.parse();
+                self.trace_line(&line, traced, res, true);
+                res
             },
 
             ParseState::Conditional => {
                 if line.starts_with("else") {
                     let current_cond = self.cond_stack.pop_back().unwrap();
                     self.cond_stack.push_back(!current_cond);
+                    if let Some(decision) = self.decisions.last_mut() {
+                        decision.in_else = true;
+                    }
                     return true;
                 }
 
                 if line.starts_with("end") {
                     self.cond_stack.pop_back();
-                    self.parse_state = ParseState::Normal;
+                    self.decisions.pop();
+                    // Only leave the conditional state once the outermost block is closed
+                    if self.cond_stack.len() == 1 {
+                        self.parse_state = ParseState::Normal;
+                    }
                     return true;
                 }
 
-                if self.cond_stack.back().unwrap() == &true {
-                    return self.parse();
+                // Every enclosing block must be active for the line to be parsed
+                if self.cond_stack.iter().all(|cond| *cond) {
+                    let res = self.parse();
+                    self.trace_line(&line, traced, res, true);
+                    return res;
                 }
 
-                // We're in a conditional but the condition is false, so we don't parse the line
-                return true;
+                // We're in a conditional but the condition is false, so we don't parse the line.
+                // Nested blocks are still tracked so that their 'end' doesn't close ours.
+                self.trace_line(&line, traced, true, false);
+                if line.starts_with("if ") || line.starts_with("try ") {
+                    self.cond_stack.push_back(false);
+                    self.push_decision(&line, false, false);
+                }
+                true
             },
             
             ParseState::Waiting => {
                 self.parse_state = ParseState::Normal;
-                return self.parse();
+                let res = self.parse();
+                self.trace_line(&line, traced, res, true);
+                res
             }
         }
     }
 
+    /// Whether the line mentions the explained hostname, directly or through a variable
+    fn mentions_explained(&self, line: &str) -> bool {
+        let hostname = match &self.explain {
+            Some(hostname) => hostname,
+            None => return false,
+        };
+        line.split_whitespace().any(|token| {
+            token == hostname || token.strip_prefix('$').and_then(|var| self.vars.get(var)) == Some(hostname)
+        })
+    }
+
+    /// Records a detail about the current line if a trace is being recorded
+    fn note(&mut self, detail: String) {
+        if self.explain.is_some() {
+            self.details.push(detail);
+        }
+    }
+
+    fn push_decision(&mut self, line: &str, result: bool, evaluated: bool) {
+        if self.explain.is_none() {
+            return;
+        }
+        let decision = Decision {
+            source: self.source(),
+            statement: line.to_owned(),
+            result,
+            evaluated,
+            in_else: false,
+            details: std::mem::take(&mut self.details),
+        };
+        self.decisions.push(decision);
+    }
+
+    fn trace_line(&mut self, line: &str, traced: bool, res: bool, active: bool) {
+        if !traced {
+            return;
+        }
+        let is_condition = line.starts_with("if ") || line.starts_with("try ");
+        let mut decisions = self.decisions.clone();
+        let outcome = if !active {
+            // Skipped lines aren't parsed, so the variables they use haven't been noted
+            for var in line.split_whitespace().filter_map(|token| token.strip_prefix('$')) {
+                if let Some(value) = self.vars.get(var) {
+                    self.details.push(format!("${} = {}", var, value));
+                }
+            }
+            Outcome::Skipped
+        } else if !res {
+            Outcome::Error
+        } else if is_condition {
+            // The line's own decision isn't one of its enclosing ones
+            let decision = decisions.pop().expect("This should never happen! (FileReader::trace_line)");
+            self.details = decision.details;
+            Outcome::Condition(decision.result)
+        } else if line.starts_with("remove ") {
+            Outcome::Removed
+        } else if line.starts_with("override ") {
+            Outcome::Overridden
+        } else if line.starts_with("set ") || line.starts_with("unset ") {
+            Outcome::Statement
+        } else {
+            Outcome::Added
+        };
+
+        let entry = TraceEntry {
+            source: self.source(),
+            line: line.to_owned(),
+            outcome,
+            details: std::mem::take(&mut self.details),
+            decisions,
+        };
+        self.trace.push(entry);
+    }
+
     fn parse_var_or_literal(&mut self, input: &str) -> String {
         if input.starts_with("$") {
             let var_name = input.trim_start_matches("$");
-            let var = self.vars.get(var_name);
+            let var = self.vars.get(var_name).cloned();
             if var.is_none() {
                 warn!("Error while reading file '{}' at line {}: Variable '{}' not found!", self.path.to_str().unwrap(), self.line_index, var_name);
+                self.note(format!("${} is not defined", var_name));
                 return String::new();
             }
-            var.unwrap().to_owned()
+            self.note(format!("${} = {}", var_name, var.as_ref().unwrap()));
+            var.unwrap()
         } else {
             input.to_owned()
         }
@@ -313,85 +992,79 @@ impl FileReader {
     }
 
     fn parse_try(&mut self, attempt: &str) -> bool {
-        // We can try one of the following things:
-        // 'ping <ip>' - Ping the IP address, return true if it responds
-        // 'file <path>' - Check if the file exists, return true if it does
-        //// 'self <ip-range>' - Check if the current IP is in the range, return true if it is
-        //// 'int <interface>' - Check if the interface exists and is up, return true if it is
-        // 'var <var-name>' - Check if the variable exists, return true if it does
-        // 'has <host>' - Check if the host has been set earlier in the file, return true if it has
-
-        let mut attempt = attempt.split_whitespace();
-        let attempt_type = attempt.next();
-        let attempt_value = attempt.next();
-
-        if attempt_type.is_none() || attempt_value.is_none() {
-            warn!("Error while reading file '{}' at line {}: Invalid try statement!", self.path.to_str().unwrap(), self.line_index);
-            return false;
+        // The available conditions are provided by the probe registry,
+        // see the 'probes' module for the built-in ones
+        let statement = match TryStatement::parse(attempt) {
+            Ok(statement) => statement,
+            Err(e) => {
+                warn!("Error while reading file '{}' at line {}: {}!", self.path.to_str().unwrap(), self.line_index, e);
+                return false;
+            }
+        };
+        for warning in &statement.warnings {
+            warn!("Error while reading file '{}' at line {}: {}!", self.path.to_str().unwrap(), self.line_index, warning);
         }
 
-        let attempt_type = attempt_type.unwrap();
-        let attempt_value = attempt_value.unwrap();
-
-        match attempt_type {
-            #[cfg(feature = "ping")]
-            "ping" => {
-                use oping::Ping;
-
-                warn!("Warning! The 'ping' feature is considered unstable. Please report any bugs you find!");
-
-                let ip = IpAddr::from_str(attempt_value);
-                if ip.is_err() {
-                    warn!("Error while reading file '{}' at line {}: Invalid IP address '{}'!", self.path.to_str().unwrap(), self.line_index, attempt_value);
-                    return false;
-                }
-                let ip = ip.unwrap();
-
-                let mut ping = Ping::new();
-
-                // Setup the ping
-                let res = ping.set_timeout(1.0);
-                let _ = unwrap_result_or_err(res,
-                                             "An error has occured while handling a ping! This should not happen", true);
-                let res = ping.set_ttl(1);
-                let _ = unwrap_result_or_err(res,
-                                             "An error has occured while handling a ping! This should not happen", true);
-                let res = ping.add_host(&ip.to_string());
-                let _ = unwrap_result_or_err(res,
-                                             "An error has occured while handling a ping! This should not happen", true);
-
-                // Send the ping
-                let res = ping.send();
-                if res.is_err() {
-                    warn!("Error while reading file '{}' at line {}: Failed to send ping!", self.path.to_str().unwrap(), self.line_index);
-                    return false;
-                }
-
-                // Get the response
-                let mut res = res.unwrap();
+        if let Some(res) = self.assumptions.probe(statement.probe, statement.argument) {
+            debug!("Using assumed result for 'try {} {}': {}", statement.probe, statement.argument, res);
+            self.note(format!("{} {} is assumed to be {}", statement.probe, statement.argument, res));
+            return res;
+        }
 
-                if res.next().unwrap().dropped <= 1 {
-                    true
-                } else {
-                    false
-                }
+        let probes = self.probes.clone();
+        let probe = match probes.get(statement.probe) {
+            Some(probe) => probe,
+            None => {
+                warn!("Error while reading file '{}' at line {}: Invalid try type '{}'!", self.path.to_str().unwrap(), self.line_index, statement.probe);
+                return false;
             }
+        };
 
-            "file" => {
-                let path = Path::new(attempt_value);
-                path.exists()
+        let ttl = match statement.ttl(probe, self.cache.default_ttl) {
+            Ok(ttl) => ttl,
+            Err(e) => {
+                warn!("Error while reading file '{}' at line {}: {}!", self.path.to_str().unwrap(), self.line_index, e);
+                return false;
             }
-
-            "var" => {
-                self.vars.contains_key(attempt_value)
+        };
+        if let Some(ttl) = ttl {
+            if let Some((res, next_boundary)) = self.cache.get(statement.probe, statement.argument, ttl) {
+                debug!("Using cached result for 'try {} {}': {}", statement.probe, statement.argument, res);
+                self.note(format!("{} {} is {} (cached)", statement.probe, statement.argument, res));
+                self.next_boundary = earliest(self.next_boundary, next_boundary);
+                return res;
             }
+        }
 
-            "has" => {
-                self.hosts.hosts.iter().any(|host| host.hostname == attempt_value)
+        let key = (statement.probe.to_owned(), statement.argument.to_owned());
+        let (res, next_boundary) = match self.prefetched.get(&key) {
+            Some((res, next_boundary)) => (res.clone(), *next_boundary),
+            None => {
+                let mut ctx = ProbeContext {
+                    path: &self.path,
+                    line: self.line_index,
+                    vars: &self.vars,
+                    hosts: &self.hosts,
+                    clock: self.clock.as_ref(),
+                    next_boundary: None,
+                };
+                let res = probe.check(statement.argument, &mut ctx);
+                (res, ctx.next_boundary)
             }
-            
-            bad => {
-                warn!("Error while reading file '{}' at line {}: Invalid try type '{}'!", self.path.to_str().unwrap(), self.line_index, bad);
+        };
+        self.next_boundary = earliest(self.next_boundary, next_boundary);
+
+        match res {
+            Ok(res) => {
+                if ttl.is_some() {
+                    self.cache.insert(statement.probe, statement.argument, res, next_boundary);
+                }
+                self.note(format!("{} {} is {}", statement.probe, statement.argument, res));
+                res
+            },
+            Err(e) => {
+                warn!("Error while reading file '{}' at line {}: {}!", self.path.to_str().unwrap(), self.line_index, e);
+                self.note(format!("{} {} failed: {}", statement.probe, statement.argument, e));
                 false
             }
         }
@@ -406,4 +1079,48 @@ impl FileReader {
 
         while self.next() {}
     }
-}
\ No newline at end of file
+}
+/// A 'try <type> <argument> [options]' statement
+pub struct TryStatement<'a> {
+    pub probe: &'a str,
+    pub argument: &'a str,
+    // Set by the 'cache=<duration>' option, '0s' never reuses a result
+    pub cache: Option<Duration>,
+    // Options that were ignored, reported when the statement is evaluated
+    pub warnings: Vec<String>,
+}
+
+impl<'a> TryStatement<'a> {
+    pub fn parse(attempt: &'a str) -> Result<Self, String> {
+        let mut parts = attempt.split_whitespace();
+        let (probe, argument) = match (parts.next(), parts.next()) {
+            (Some(probe), Some(argument)) => (probe, argument),
+            _ => return Err("Invalid try statement".to_string()),
+        };
+
+        let mut cache = None;
+        let mut warnings = vec![];
+        for option in parts {
+            match option.split_once('=') {
+                Some(("cache", value)) => match humantime::parse_duration(value) {
+                    Ok(value) => cache = Some(value),
+                    Err(_) => warnings.push(format!("Invalid cache duration '{}'", value)),
+                },
+                _ => warnings.push(format!("Unknown try option '{}'", option)),
+            }
+        }
+
+        Ok(TryStatement { probe, argument, cache, warnings })
+    }
+
+    /// How long a previous result may be reused, if at all. Results of probes that
+    /// depend on the evaluator's state would go stale, so they can't be cached.
+    pub fn ttl(&self, probe: &dyn Probe, default_ttl: Duration) -> Result<Option<Duration>, String> {
+        match self.cache {
+            Some(_) if !probe.cacheable() => Err(format!("The result of '{}' can't be cached", probe.name())),
+            Some(ttl) => Ok(Some(ttl)),
+            None if probe.cacheable() => Ok(Some(default_ttl)),
+            None => Ok(None),
+        }
+    }
+}
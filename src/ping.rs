@@ -0,0 +1,130 @@
+use log::*;
+
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+const PAYLOAD: &[u8] = b"drophost";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PingOptions {
+    /// Number of echo requests to send, the host is reachable if any is answered
+    pub count: u16,
+    /// Time to wait for each reply
+    pub timeout: Duration,
+    /// TTL (IPv4) or hop limit (IPv6) of the requests
+    pub ttl: u32,
+}
+
+impl Default for PingOptions {
+    fn default() -> Self {
+        PingOptions {
+            count: 1,
+            timeout: Duration::from_secs(1),
+            ttl: 64,
+        }
+    }
+}
+
+/// Pings the address using an unprivileged ICMP socket. This requires the
+/// group of the current user to be allowed by `net.ipv4.ping_group_range`,
+/// which also applies to IPv6.
+pub fn ping(ip: IpAddr, options: &PingOptions) -> io::Result<bool> {
+    let (domain, protocol) = match ip {
+        IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+        IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+    };
+
+    let socket = Socket::new(domain, Type::DGRAM, Some(protocol)).map_err(|e| {
+        if e.kind() == ErrorKind::PermissionDenied {
+            io::Error::new(e.kind(), "ICMP sockets are not allowed for this user, check the net.ipv4.ping_group_range sysctl")
+        } else {
+            e
+        }
+    })?;
+    match ip {
+        IpAddr::V4(_) => socket.set_ttl_v4(options.ttl)?,
+        IpAddr::V6(_) => socket.set_unicast_hops_v6(options.ttl)?,
+    }
+    // Datagram ICMP sockets behave like UDP sockets for sending and receiving
+    let socket = UdpSocket::from(socket);
+    let target = SocketAddr::new(ip, 0);
+
+    for sequence in 0..options.count {
+        socket.send_to(&echo_request(ip.is_ipv6(), sequence), target)?;
+        if wait_for_reply(&socket, ip.is_ipv6(), sequence, options.timeout)? {
+            return Ok(true);
+        }
+        debug!("No reply from {} for ping #{}", ip, sequence);
+    }
+    Ok(false)
+}
+
+fn wait_for_reply(socket: &UdpSocket, v6: bool, sequence: u16, timeout: Duration) -> io::Result<bool> {
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 1024];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(false);
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        match socket.recv(&mut buf) {
+            Ok(len) => {
+                if is_echo_reply(&buf[..len], v6, sequence) {
+                    return Ok(true);
+                }
+                // Replies to earlier requests or other ICMP messages, keep waiting
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Builds an ICMP echo request. The identifier is filled in by the kernel
+/// for datagram sockets, as is the checksum for ICMPv6.
+pub fn echo_request(v6: bool, sequence: u16) -> Vec<u8> {
+    let mut packet = vec![0u8; 8];
+    packet[0] = if v6 { ICMPV6_ECHO_REQUEST } else { ICMP_ECHO_REQUEST };
+    packet[6..8].copy_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(PAYLOAD);
+
+    if !v6 {
+        let checksum = checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    packet
+}
+
+/// Checks if the packet, without its IP header, is a reply to the given request
+pub fn is_echo_reply(packet: &[u8], v6: bool, sequence: u16) -> bool {
+    if packet.len() < 8 {
+        return false;
+    }
+    let reply_type = if v6 { ICMPV6_ECHO_REPLY } else { ICMP_ECHO_REPLY };
+    packet[0] == reply_type && packet[6..8] == sequence.to_be_bytes()
+}
+
+/// Internet checksum (RFC 1071)
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|chunk| match chunk {
+            [a, b] => u16::from_be_bytes([*a, *b]) as u32,
+            [a] => u16::from_be_bytes([*a, 0]) as u32,
+            _ => 0,
+        })
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
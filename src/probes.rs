@@ -0,0 +1,421 @@
+use log::*;
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{Datelike, NaiveDateTime};
+use once_cell::sync::Lazy;
+use tokio::runtime::RuntimeFlavor;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::clock::{earliest, Clock, DayWindow, TimeWindow};
+#[cfg(feature = "ping")]
+use crate::ping::{self, PingOptions};
+use crate::types::Hosts;
+
+/// State of the evaluator made available to a probe while it runs
+pub struct ProbeContext<'a> {
+    pub path: &'a Path,
+    pub line: usize,
+    pub vars: &'a HashMap<String, String>,
+    pub hosts: &'a Hosts,
+    pub clock: &'a dyn Clock,
+
+    // Earliest time at which a probe's result is known to change
+    pub next_boundary: Option<NaiveDateTime>,
+}
+
+impl ProbeContext<'_> {
+    /// Ask for the configuration to be re-evaluated at the given time
+    pub fn schedule(&mut self, at: NaiveDateTime) {
+        self.next_boundary = earliest(self.next_boundary, Some(at));
+    }
+}
+
+/// A condition that can be checked with `try <name> <argument>`
+pub trait Probe: Send + Sync {
+    /// Name used after `try` in configuration files
+    fn name(&self) -> &str;
+
+    /// Short description shown by `--list-probes`
+    fn description(&self) -> &str;
+
+    /// Check the condition. Errors are reported as warnings and make the
+    /// condition false.
+    fn check(&self, argument: &str, ctx: &mut ProbeContext) -> Result<bool, String>;
+
+    /// Whether results can be reused, for the global cache TTL or the one set
+    /// with `cache=<duration>`. Probes that depend on the evaluator's state
+    /// should leave this off, `cache=` is then an error.
+    fn cacheable(&self) -> bool {
+        false
+    }
+
+    /// Whether the result only depends on the argument, so that the probe
+    /// can be run ahead of evaluation, concurrently with other probes.
+    /// Slow network checks should turn this on.
+    fn prefetch(&self) -> bool {
+        false
+    }
+}
+
+/// The set of probes available to `try` statements
+#[derive(Clone)]
+pub struct ProbeRegistry {
+    probes: BTreeMap<String, Arc<dyn Probe>>,
+}
+
+impl ProbeRegistry {
+    /// Creates a registry without any probe, not even the built-in ones
+    pub fn empty() -> Self {
+        ProbeRegistry { probes: BTreeMap::new() }
+    }
+
+    /// Creates a registry with all of the built-in probes
+    pub fn new() -> Self {
+        let mut registry = Self::empty();
+        #[cfg(feature = "ping")]
+        registry.register(PingProbe::default());
+        registry.register(FileProbe);
+        registry.register(VarProbe);
+        registry.register(HasProbe);
+        registry.register(TimeProbe);
+        registry.register(DayProbe);
+        registry
+    }
+
+    /// Registers a probe, replacing any existing probe with the same name
+    pub fn register<P: Probe + 'static>(&mut self, probe: P) {
+        let name = probe.name().to_owned();
+        if self.probes.insert(name.clone(), Arc::new(probe)).is_some() {
+            debug!("Replacing existing probe '{}'", name);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Probe> {
+        self.probes.get(name).map(|probe| probe.as_ref())
+    }
+
+    /// Same as `get`, for callers that need to move the probe to another thread
+    pub fn get_shared(&self, name: &str) -> Option<Arc<dyn Probe>> {
+        self.probes.get(name).cloned()
+    }
+
+    /// All registered probes, sorted by name
+    pub fn probes(&self) -> impl Iterator<Item = &dyn Probe> {
+        self.probes.values().map(|probe| probe.as_ref())
+    }
+}
+
+impl Default for ProbeRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Default time for which cacheable probe results are reused
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(30);
+
+struct CachedResult {
+    result: bool,
+    next_boundary: Option<NaiveDateTime>,
+    stored_at: Instant,
+}
+
+/// Results of previous probes, keyed by probe name and argument. A single
+/// cache can be shared between evaluations so that re-runs in watch mode
+/// don't repeat slow probes.
+pub struct ProbeCache {
+    pub default_ttl: Duration,
+    entries: Mutex<HashMap<(String, String), CachedResult>>,
+}
+
+impl ProbeCache {
+    pub fn new(default_ttl: Duration) -> Self {
+        ProbeCache {
+            default_ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the stored result and re-evaluation time if it is younger than `ttl`
+    pub fn get(&self, probe: &str, argument: &str, ttl: Duration) -> Option<(bool, Option<NaiveDateTime>)> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&(probe.to_owned(), argument.to_owned()))?;
+        if entry.stored_at.elapsed() < ttl {
+            Some((entry.result, entry.next_boundary))
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(&self, probe: &str, argument: &str, result: bool, next_boundary: Option<NaiveDateTime>) {
+        let entry = CachedResult {
+            result,
+            next_boundary,
+            stored_at: Instant::now(),
+        };
+        self.entries.lock().unwrap().insert((probe.to_owned(), argument.to_owned()), entry);
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+impl Default for ProbeCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_TTL)
+    }
+}
+
+/// Default time given to prefetched probes before they are considered failed
+pub const DEFAULT_PROBE_DEADLINE: Duration = Duration::from_secs(5);
+
+/// Probe name and argument
+pub type ProbeKey = (String, String);
+/// Result of a probe and the time at which it should be re-evaluated
+pub type ProbeOutcome = (Result<bool, String>, Option<NaiveDateTime>);
+
+/// A probe to run ahead of evaluation, with the first statement that uses it
+pub struct ProbeJob {
+    pub probe: Arc<dyn Probe>,
+    pub argument: String,
+    pub path: PathBuf,
+    pub line: usize,
+}
+
+/// Most probes running at once, across every evaluation
+pub const MAX_CONCURRENT_PROBES: usize = 32;
+
+// Shared by every evaluation, so that probes still running past the deadline of
+// an earlier run in watch mode keep their slot until they return
+static PROBE_SLOTS: Lazy<Arc<Semaphore>> = Lazy::new(|| Arc::new(Semaphore::new(MAX_CONCURRENT_PROBES)));
+
+/// Runs all of the jobs concurrently on tokio's blocking thread pool, at most
+/// `MAX_CONCURRENT_PROBES` at a time. Jobs that haven't finished once the deadline
+/// has passed are reported as failed. The ones that haven't started yet are cancelled,
+/// the ones already running can't be interrupted and are left to return on their own.
+pub fn run_concurrently(jobs: Vec<ProbeJob>, clock: Arc<dyn Clock>, deadline: Duration) -> HashMap<ProbeKey, ProbeOutcome> {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| handle.block_on(run_jobs(jobs, clock, deadline)))
+        },
+        // Only a multi-threaded runtime can be blocked, the jobs get a runtime of their own
+        // on another thread, where no runtime is running
+        Ok(_) => std::thread::scope(|scope| {
+            scope.spawn(|| run_on_own_runtime(jobs, clock, deadline))
+                .join()
+                .expect("This should never happen! (run_concurrently)")
+        }),
+        Err(_) => run_on_own_runtime(jobs, clock, deadline),
+    }
+}
+
+fn run_on_own_runtime(jobs: Vec<ProbeJob>, clock: Arc<dyn Clock>, deadline: Duration) -> HashMap<ProbeKey, ProbeOutcome> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .expect("Could not start the probe runtime");
+    let results = runtime.block_on(run_jobs(jobs, clock, deadline));
+    // Don't wait for the probes that are still running
+    runtime.shutdown_background();
+    results
+}
+
+async fn run_jobs(jobs: Vec<ProbeJob>, clock: Arc<dyn Clock>, deadline: Duration) -> HashMap<ProbeKey, ProbeOutcome> {
+    let mut results = HashMap::new();
+    let mut set = JoinSet::new();
+    for job in jobs {
+        let key = (job.probe.name().to_owned(), job.argument.clone());
+        let timeout = format!("Did not finish within the {} probe deadline", humantime::format_duration(deadline));
+        results.insert(key.clone(), (Err(timeout), None));
+        set.spawn(run_job(key, job, clock.clone()));
+    }
+
+    let remaining = tokio::time::timeout(deadline, async {
+        while let Some(res) = set.join_next().await {
+            match res {
+                Ok((key, outcome)) => {
+                    results.insert(key, outcome);
+                },
+                Err(e) => warn!("A probe stopped unexpectedly: {}", e),
+            }
+        }
+    }).await;
+    if remaining.is_err() {
+        warn!("{} probe(s) did not finish within the {} deadline", set.len(), humantime::format_duration(deadline));
+        set.abort_all();
+    }
+    results
+}
+
+async fn run_job(key: ProbeKey, job: ProbeJob, clock: Arc<dyn Clock>) -> (ProbeKey, ProbeOutcome) {
+    let permit = PROBE_SLOTS.clone().acquire_owned().await.expect("This should never happen! (run_job)");
+    let outcome = tokio::task::spawn_blocking(move || {
+        // Released once the probe returns, even if nobody is waiting for it anymore
+        let _permit = permit;
+        // Prefetched probes don't get to see the evaluator's state
+        let vars = HashMap::new();
+        let hosts = Hosts::new();
+        let mut ctx = ProbeContext {
+            path: &job.path,
+            line: job.line,
+            vars: &vars,
+            hosts: &hosts,
+            clock: clock.as_ref(),
+            next_boundary: None,
+        };
+        let res = job.probe.check(&job.argument, &mut ctx);
+        (res, ctx.next_boundary)
+    }).await;
+    match outcome {
+        Ok(outcome) => (key, outcome),
+        Err(e) => (key, (Err(format!("Probe failed: {}", e)), None)),
+    }
+}
+
+/// 'ping <host>' - Ping the IP address or hostname, true if it responds
+#[cfg(feature = "ping")]
+#[derive(Default)]
+pub struct PingProbe {
+    pub options: PingOptions,
+}
+
+#[cfg(feature = "ping")]
+impl PingProbe {
+    pub fn new(options: PingOptions) -> Self {
+        PingProbe { options }
+    }
+}
+
+#[cfg(feature = "ping")]
+impl Probe for PingProbe {
+    fn name(&self) -> &str {
+        "ping"
+    }
+
+    fn description(&self) -> &str {
+        "Checks if the given IP address or hostname responds to a ping"
+    }
+
+    fn check(&self, argument: &str, _ctx: &mut ProbeContext) -> Result<bool, String> {
+        use std::net::{IpAddr, ToSocketAddrs};
+
+        let ip = match IpAddr::from_str(argument) {
+            Ok(ip) => ip,
+            Err(_) => (argument, 0).to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .map(|addr| addr.ip())
+                .ok_or(format!("Could not resolve '{}'", argument))?,
+        };
+
+        ping::ping(ip, &self.options).map_err(|e| format!("Failed to ping {}: {}", ip, e))
+    }
+
+    fn cacheable(&self) -> bool {
+        true
+    }
+
+    fn prefetch(&self) -> bool {
+        true
+    }
+}
+
+/// 'file <path>' - Check if the file exists
+pub struct FileProbe;
+
+impl Probe for FileProbe {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn description(&self) -> &str {
+        "Checks if a file exists at the given path"
+    }
+
+    fn check(&self, argument: &str, _ctx: &mut ProbeContext) -> Result<bool, String> {
+        Ok(Path::new(argument).exists())
+    }
+}
+
+/// 'var <var-name>' - Check if the variable exists
+pub struct VarProbe;
+
+impl Probe for VarProbe {
+    fn name(&self) -> &str {
+        "var"
+    }
+
+    fn description(&self) -> &str {
+        "Checks if a variable is defined"
+    }
+
+    fn check(&self, argument: &str, ctx: &mut ProbeContext) -> Result<bool, String> {
+        Ok(ctx.vars.contains_key(argument))
+    }
+}
+
+/// 'has <host>' - Check if the host has been set earlier in the file
+pub struct HasProbe;
+
+impl Probe for HasProbe {
+    fn name(&self) -> &str {
+        "has"
+    }
+
+    fn description(&self) -> &str {
+        "Checks if a hostname has been previously defined"
+    }
+
+    fn check(&self, argument: &str, ctx: &mut ProbeContext) -> Result<bool, String> {
+        Ok(ctx.hosts.hosts.iter().any(|host| host.hostname == argument))
+    }
+}
+
+/// 'time <HH:MM-HH:MM>' - Check if the local time is within the window
+pub struct TimeProbe;
+
+impl Probe for TimeProbe {
+    fn name(&self) -> &str {
+        "time"
+    }
+
+    fn description(&self) -> &str {
+        "Checks if the local time is within a window (e.g. 09:00-17:30)"
+    }
+
+    fn check(&self, argument: &str, ctx: &mut ProbeContext) -> Result<bool, String> {
+        let window = TimeWindow::from_str(argument)?;
+        let now = ctx.clock.now();
+        ctx.schedule(window.next_boundary(now));
+        Ok(window.contains(now.time()))
+    }
+}
+
+/// 'day <days>' - Check if today is one of the given days (e.g. 'mon-fri')
+pub struct DayProbe;
+
+impl Probe for DayProbe {
+    fn name(&self) -> &str {
+        "day"
+    }
+
+    fn description(&self) -> &str {
+        "Checks if today is one of the given days (e.g. mon-fri or sat,sun)"
+    }
+
+    fn check(&self, argument: &str, ctx: &mut ProbeContext) -> Result<bool, String> {
+        let window = DayWindow::from_str(argument)?;
+        let now = ctx.clock.now();
+        if let Some(boundary) = window.next_boundary(now) {
+            ctx.schedule(boundary);
+        }
+        Ok(window.contains(now.weekday()))
+    }
+}
@@ -0,0 +1,125 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::types::{Host, Hosts};
+use crate::writer::{Format, OutputOptions, SortOrder};
+
+/// Which entries are written to a target
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Filter {
+    /// Only write entries with at least one of these tags, every entry if empty
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Filter {
+    pub fn matches(&self, hosts: &Hosts, host: &Host) -> bool {
+        self.tags.is_empty() || hosts.tags_of(host).iter().any(|tag| self.tags.contains(tag))
+    }
+
+    pub fn apply(&self, hosts: &Hosts) -> Hosts {
+        let mut filtered = Hosts::from(hosts.hosts.iter().filter(|host| self.matches(hosts, host)).cloned().collect());
+        filtered.tags = hosts.tags.clone();
+        filtered
+    }
+}
+
+/// A file generated from the evaluated entries. Options that aren't set are
+/// taken from the command line flags with the same name.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Target {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: Format,
+    #[serde(default)]
+    pub filter: Filter,
+    /// Origin of the zone written by the bind format
+    pub zone: Option<String>,
+    pub provenance: Option<bool>,
+    pub managed_block: Option<bool>,
+    pub group: Option<bool>,
+    pub sort: Option<SortOrder>,
+    pub split_families: Option<bool>,
+}
+
+impl Target {
+    pub fn new(path: PathBuf, format: Format) -> Self {
+        Target {
+            path,
+            format,
+            filter: Filter::default(),
+            zone: None,
+            provenance: None,
+            managed_block: None,
+            group: None,
+            sort: None,
+            split_families: None,
+        }
+    }
+
+    /// Options to write this target with, `defaults` being the ones of the command line
+    pub fn output_options(&self, defaults: &OutputOptions) -> OutputOptions {
+        OutputOptions {
+            format: self.format,
+            provenance: self.provenance.unwrap_or(defaults.provenance),
+            managed_block: self.managed_block.unwrap_or(defaults.managed_block),
+            group: self.group.unwrap_or(defaults.group),
+            sort: self.sort.unwrap_or(defaults.sort),
+            split_families: self.split_families.unwrap_or(defaults.split_families),
+            zone: self.zone.clone().or(defaults.zone.clone()),
+        }
+    }
+}
+
+// Options that can be given alone, as in 'group', or with a value, as in 'group=false'
+fn flag(value: Option<&str>, option: &str, target: &str) -> Result<Option<bool>, String> {
+    match value {
+        None | Some("true") => Ok(Some(true)),
+        Some("false") => Ok(Some(false)),
+        Some(value) => Err(format!("Invalid value '{}' for '{}' in target '{}', expected true or false", value, option, target)),
+    }
+}
+
+/// Parses targets given on the command line, as in
+/// '/etc/dnsmasq.d/lan.conf,format=dnsmasq,tag=lan' or '/etc/bind/db.lan,format=bind,zone=lan,sort=ip'
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let path = parts.next().filter(|path| !path.is_empty())
+            .ok_or(format!("Missing path in target '{}'", s))?;
+        let mut target = Target::new(PathBuf::from(path), Format::default());
+
+        for option in parts {
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (option, None),
+            };
+            match (name, value) {
+                ("format", Some(format)) => {
+                    target.format = Format::from_str(format, true)
+                        .map_err(|_| format!("Unknown format '{}' in target '{}'", format, s))?;
+                },
+                ("tag", Some(tag)) => target.filter.tags.push(tag.to_owned()),
+                ("zone", Some(zone)) => target.zone = Some(zone.to_owned()),
+                ("sort", Some(sort)) => {
+                    target.sort = Some(SortOrder::from_str(sort, true)
+                        .map_err(|_| format!("Unknown sort order '{}' in target '{}'", sort, s))?);
+                },
+                ("provenance", _) => target.provenance = flag(value, name, s)?,
+                ("managed-block", _) => target.managed_block = flag(value, name, s)?,
+                ("group", _) => target.group = flag(value, name, s)?,
+                ("split-families", _) => target.split_families = flag(value, name, s)?,
+                _ => return Err(format!("Invalid option '{}' in target '{}', expected format=<format>, tag=<tag>, zone=<zone>, \
+                    sort=<order>, provenance, managed-block, group or split-families", option, s)),
+            }
+        }
+        Ok(target)
+    }
+}
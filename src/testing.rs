@@ -0,0 +1,164 @@
+use log::*;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDateTime;
+use serde::Deserialize;
+use similar::{ChangeTag, TextDiff};
+
+use crate::assume::{Assumption, Assumptions};
+use crate::clock::FixedClock;
+use crate::parser::DirReader;
+use crate::probes::{Probe, ProbeContext, ProbeRegistry};
+use crate::types::{Host, Hosts};
+
+/// Conditions that only depend on the evaluation and on `now`, every other one
+/// must be assumed so that test cases give the same result on every machine
+pub const EVALUATED_PROBES: [&str; 4] = ["var", "has", "time", "day"];
+
+/// Local time used when a test case doesn't set `now`, a Monday
+pub const DEFAULT_NOW: &str = "2024-01-01T00:00:00";
+
+/// A test case for a drop-in directory, read from a TOML file:
+///
+/// ```toml
+/// assume = ["ping:10.0.0.1=down"]
+/// now = "2024-01-03T10:00:00"
+/// expect = ["203.0.113.1 office"]
+///
+/// [vars]
+/// sys_hostname = "box"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestCase {
+    /// Probe results and variables, in the same format as `--assume`
+    #[serde(default)]
+    pub assume: Vec<String>,
+    /// The only variables defined at the start, `sys_*` and `env_*` variables
+    /// are not taken from the system running the test
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    /// Local time used by time-based conditions, `DEFAULT_NOW` if not set
+    pub now: Option<NaiveDateTime>,
+    /// Every entry expected in the output, in order
+    pub expect: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct TestResult {
+    pub name: String,
+    pub passed: bool,
+    /// Lines of the expected output prefixed with '-' when missing and
+    /// lines of the actual output prefixed with '+' when unexpected
+    pub diff: Vec<String>,
+}
+
+impl TestCase {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read '{}': {}", path.display(), e))?;
+        toml::from_str(&contents)
+            .map_err(|e| format!("Could not parse '{}': {}", path.display(), e))
+    }
+
+    /// Evaluates the drop-in directory with this test case's facts. Conditions
+    /// that depend on the machine running the test must be assumed.
+    pub fn evaluate(&self, config_dir: &Path) -> Result<Hosts, String> {
+        if !config_dir.is_dir() {
+            return Err(format!("Drop-in directory '{}' does not exist", config_dir.display()));
+        }
+        let assumptions = self.assume.iter()
+            .map(|a| Assumption::from_str(a))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut dir_reader = DirReader::with_vars(config_dir, self.vars.clone());
+        dir_reader.assume(Assumptions::new(assumptions));
+        let now = self.now.unwrap_or_else(|| DEFAULT_NOW.parse().expect("This should never happen! (TestCase::evaluate)"));
+        dir_reader.clock = Arc::new(FixedClock(now));
+
+        let unassumed = Arc::new(Mutex::new(vec![]));
+        for probe in ProbeRegistry::new().probes().filter(|probe| !EVALUATED_PROBES.contains(&probe.name())) {
+            dir_reader.register_probe(UnassumedProbe {
+                name: probe.name().to_owned(),
+                unassumed: unassumed.clone(),
+            });
+        }
+        dir_reader.parse_all();
+
+        let unassumed = unassumed.lock().unwrap();
+        if !unassumed.is_empty() {
+            return Err(format!("Conditions must be assumed in test cases: {}", unassumed.join(", ")));
+        }
+        Ok(*dir_reader.hosts)
+    }
+
+    pub fn run(&self, name: &str, config_dir: &Path) -> Result<TestResult, String> {
+        let expected = self.expect.iter()
+            .map(|entry| Host::parse_entry(entry)
+                .map(|host| host.to_string())
+                .ok_or(format!("Invalid expected entry '{}'", entry)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let actual = self.evaluate(config_dir)?
+            .hosts
+            .iter()
+            .map(|host| host.to_string())
+            .collect::<Vec<_>>();
+
+        let expected = expected.join("\n") + "\n";
+        let actual = actual.join("\n") + "\n";
+        let diff = TextDiff::from_lines(&expected, &actual)
+            .iter_all_changes()
+            .filter_map(|change| match change.tag() {
+                ChangeTag::Delete => Some(format!("- {}", change.value().trim_end())),
+                ChangeTag::Insert => Some(format!("+ {}", change.value().trim_end())),
+                ChangeTag::Equal => None,
+            })
+            .collect::<Vec<_>>();
+
+        Ok(TestResult {
+            name: name.to_owned(),
+            passed: diff.is_empty(),
+            diff,
+        })
+    }
+}
+
+// Stands in for a condition that depends on the machine, records the statements using it
+struct UnassumedProbe {
+    name: String,
+    unassumed: Arc<Mutex<Vec<String>>>,
+}
+
+impl Probe for UnassumedProbe {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        "Must be assumed in test cases"
+    }
+
+    fn check(&self, argument: &str, ctx: &mut ProbeContext) -> Result<bool, String> {
+        let statement = format!("'{} {}' ({}:{})", self.name, argument, ctx.path.display(), ctx.line);
+        self.unassumed.lock().unwrap().push(statement);
+        Err(format!("'{} {}' is not assumed", self.name, argument))
+    }
+}
+
+/// Finds every '*.toml' test case in the directory, sorted by name
+pub fn find_cases(cases_dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(cases_dir)
+        .map_err(|e| format!("Could not read test directory '{}': {}", cases_dir.display(), e))?;
+    let mut cases = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .collect::<Vec<_>>();
+    cases.sort();
+    debug!("Found {} test case(s) in '{}'", cases.len(), cases_dir.display());
+    Ok(cases)
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::module_inception)]
+
 #[cfg(test)]
 mod tests {
     use std::{path::Path, fs::File, io::{BufReader, BufRead}};
@@ -61,18 +63,22 @@ mod tests {
             Host {
                 ip: "127.0.0.1".to_string(),
                 hostname: "localhost".to_string(),
+                source: None,
             },
             Host {
                 ip: "8.8.8.8".to_string(),
                 hostname: "goog".to_string(),
+                source: None,
             },
             Host {
                 ip: "1234.1234.1234.1234".to_string(),
                 hostname: "stacked".to_string(),
+                source: None,
             },
             Host {
                 ip: "1.1.1.1".to_string(),
                 hostname: "should_appear".to_string(),
+                source: None,
             }
         ];
 
@@ -125,4 +131,941 @@ mod tests {
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_time_window_conditions() {
+        use crate::parser::FileReader;
+        use crate::clock::FixedClock;
+        use std::path::Path;
+        use std::sync::Arc;
+        use std::collections::HashMap;
+        use chrono::NaiveDate;
+        // Wednesday morning, during work hours
+        let now = NaiveDate::from_ymd_opt(2024, 1, 3).unwrap().and_hms_opt(10, 0, 0).unwrap();
+        let path = Path::new("tests/time_window.conf");
+        let mut file = FileReader::new(path, HashMap::new());
+        file.clock = Arc::new(FixedClock(now));
+        file.parse_all();
+        assert_eq!(file.hosts.hosts, vec![Host::new("distracting.example".to_string(), "0.0.0.0".to_string())]);
+        // The work hours window closes before the night window opens or the weekend starts
+        let expected_boundary = NaiveDate::from_ymd_opt(2024, 1, 3).unwrap().and_hms_opt(17, 30, 0).unwrap();
+        assert_eq!(file.next_boundary, Some(expected_boundary));
+    }
+
+    #[test]
+    fn test_time_window_outside_hours() {
+        use crate::parser::FileReader;
+        use crate::clock::FixedClock;
+        use std::path::Path;
+        use std::sync::Arc;
+        use std::collections::HashMap;
+        use chrono::NaiveDate;
+        // Saturday, late at night
+        let now = NaiveDate::from_ymd_opt(2024, 1, 6).unwrap().and_hms_opt(23, 15, 0).unwrap();
+        let path = Path::new("tests/time_window.conf");
+        let mut file = FileReader::new(path, HashMap::new());
+        file.clock = Arc::new(FixedClock(now));
+        file.parse_all();
+        let hostnames: Vec<_> = file.hosts.hosts.iter().map(|h| h.hostname.as_str()).collect();
+        assert_eq!(hostnames, vec!["night_only", "weekend_only"]);
+        let expected_boundary = NaiveDate::from_ymd_opt(2024, 1, 7).unwrap().and_hms_opt(6, 0, 0).unwrap();
+        assert_eq!(file.next_boundary, Some(expected_boundary));
+    }
+
+    #[test]
+    fn test_parse_windows() {
+        use crate::clock::{TimeWindow, DayWindow};
+        use chrono::{NaiveDate, NaiveTime, Weekday};
+        use std::str::FromStr;
+        let window = TimeWindow::from_str("22:00-06:00").unwrap();
+        assert!(window.contains(NaiveTime::from_hms_opt(23, 0, 0).unwrap()));
+        assert!(window.contains(NaiveTime::from_hms_opt(5, 59, 0).unwrap()));
+        assert!(!window.contains(NaiveTime::from_hms_opt(6, 0, 0).unwrap()));
+        assert!(TimeWindow::from_str("09:00").is_err());
+        assert!(TimeWindow::from_str("25:00-26:00").is_err());
+
+        let days = DayWindow::from_str("fri-mon").unwrap();
+        assert!(days.contains(Weekday::Sun));
+        assert!(days.contains(Weekday::Mon));
+        assert!(!days.contains(Weekday::Wed));
+        assert!(DayWindow::from_str("someday").is_err());
+
+        // Every day: nothing ever changes
+        let now = NaiveDate::from_ymd_opt(2024, 1, 3).unwrap().and_hms_opt(10, 0, 0).unwrap();
+        assert_eq!(DayWindow::from_str("mon-sun").unwrap().next_boundary(now), None);
+    }
+
+    struct MockFacts;
+
+    impl crate::facts::FactsProvider for MockFacts {
+        fn hostname(&self) -> Option<String> { Some("box".to_string()) }
+        fn fqdn(&self) -> Option<String> { Some("box.example.com".to_string()) }
+        fn os_id(&self) -> Option<String> { Some("arch".to_string()) }
+        fn machine_id(&self) -> Option<String> { None }
+        fn arch(&self) -> Option<String> { Some("x86_64".to_string()) }
+        fn kernel(&self) -> Option<String> { Some("6.1.0".to_string()) }
+        fn uid(&self) -> Option<String> { Some("0".to_string()) }
+    }
+
+    #[test]
+    fn test_system_facts() {
+        use crate::parser::DirReader;
+        use std::path::Path;
+        let mut dir_reader = DirReader::with_facts(Path::new("tests/facts_dir"), &MockFacts);
+        assert_eq!(dir_reader.vars.get("sys_kernel"), Some(&"6.1.0".to_string()));
+        assert!(!dir_reader.vars.contains_key("sys_machine_id"));
+        dir_reader.parse_all();
+        let hostnames: Vec<_> = dir_reader.hosts.hosts.iter().map(|h| h.hostname.as_str()).collect();
+        assert_eq!(hostnames, vec!["box.example.com", "box", "arch-mirror"]);
+    }
+
+    #[test]
+    fn test_parse_os_release() {
+        use crate::facts::parse_os_release_id;
+        let contents = "NAME=\"Debian GNU/Linux\"\nVERSION_ID=\"12\"\nID=debian\n";
+        assert_eq!(parse_os_release_id(contents), Some("debian".to_string()));
+        assert_eq!(parse_os_release_id("ID=\"opensuse-tumbleweed\""), Some("opensuse-tumbleweed".to_string()));
+        assert_eq!(parse_os_release_id("NAME=nothing"), None);
+    }
+
+    struct VpnProbe;
+
+    impl crate::probes::Probe for VpnProbe {
+        fn name(&self) -> &str { "vpn" }
+        fn description(&self) -> &str { "Checks if the given VPN is connected" }
+        fn check(&self, argument: &str, _ctx: &mut crate::probes::ProbeContext) -> Result<bool, String> {
+            Ok(argument == "office")
+        }
+    }
+
+    #[test]
+    fn test_custom_probe() {
+        use crate::parser::DirReader;
+        use std::path::Path;
+        let mut dir_reader = DirReader::new(Path::new("tests/probe_dir"));
+        dir_reader.register_probe(VpnProbe);
+        dir_reader.parse_all();
+        assert_eq!(dir_reader.hosts.hosts, vec![Host::new("intranet".to_string(), "10.0.0.1".to_string())]);
+    }
+
+    #[test]
+    fn test_builtin_probes_registered() {
+        use crate::probes::ProbeRegistry;
+        let registry = ProbeRegistry::new();
+        let names: Vec<_> = registry.probes().map(|p| p.name()).collect();
+        for name in ["file", "var", "has", "time", "day"] {
+            assert!(names.contains(&name), "missing built-in probe '{}'", name);
+        }
+        assert!(ProbeRegistry::empty().get("file").is_none());
+    }
+
+    struct CountingProbe(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    impl crate::probes::Probe for CountingProbe {
+        fn name(&self) -> &str { "counter" }
+        fn description(&self) -> &str { "Counts how many times it was run" }
+        fn check(&self, _argument: &str, _ctx: &mut crate::probes::ProbeContext) -> Result<bool, String> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(true)
+        }
+        fn cacheable(&self) -> bool { true }
+    }
+
+    #[test]
+    fn test_probe_cache_shared_between_runs() {
+        use crate::parser::DirReader;
+        use crate::probes::ProbeCache;
+        use std::path::Path;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+        let count = Arc::new(AtomicUsize::new(0));
+        let cache = Arc::new(ProbeCache::new(Duration::from_secs(60)));
+        for _ in 0..2 {
+            let mut dir_reader = DirReader::new(Path::new("tests/cache_dir"));
+            dir_reader.register_probe(CountingProbe(count.clone()));
+            dir_reader.cache = cache.clone();
+            dir_reader.parse_all();
+            assert_eq!(dir_reader.hosts.hosts.len(), 3);
+        }
+        // 'a' is only probed once across both runs, 'b' opts out of caching
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        cache.clear();
+        let mut dir_reader = DirReader::new(Path::new("tests/cache_dir"));
+        dir_reader.register_probe(CountingProbe(count.clone()));
+        dir_reader.cache = cache.clone();
+        dir_reader.parse_all();
+        assert_eq!(count.load(Ordering::SeqCst), 5);
+
+        // Results that depend on the files being evaluated would go stale
+        use crate::parser::TryStatement;
+        use crate::probes::{HasProbe, VarProbe};
+        let statement = TryStatement::parse("has nas cache=1m").unwrap();
+        assert!(statement.ttl(&HasProbe, Duration::from_secs(30)).is_err());
+        assert_eq!(TryStatement::parse("var x").unwrap().ttl(&VarProbe, Duration::from_secs(30)), Ok(None));
+        // Unknown options are warned about and ignored
+        let statement = TryStatement::parse("counter a cache=1m retries=3").unwrap();
+        assert_eq!(statement.warnings, vec!["Unknown try option 'retries=3'"]);
+        assert_eq!(statement.ttl(&CountingProbe(count), Duration::from_secs(30)), Ok(Some(Duration::from_secs(60))));
+    }
+
+    struct SlowProbe(std::time::Duration);
+
+    impl crate::probes::Probe for SlowProbe {
+        fn name(&self) -> &str { "slow" }
+        fn description(&self) -> &str { "Takes a while to answer" }
+        fn check(&self, _argument: &str, _ctx: &mut crate::probes::ProbeContext) -> Result<bool, String> {
+            std::thread::sleep(self.0);
+            Ok(true)
+        }
+        fn prefetch(&self) -> bool { true }
+    }
+
+    #[test]
+    fn test_concurrent_probes() {
+        use crate::parser::DirReader;
+        use std::path::Path;
+        use std::time::{Duration, Instant};
+        let mut dir_reader = DirReader::new(Path::new("tests/slow_dir"));
+        dir_reader.register_probe(SlowProbe(Duration::from_millis(300)));
+        let start = Instant::now();
+        dir_reader.parse_all();
+        // Ten probes run one after the other would take at least 3 seconds
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(dir_reader.hosts.hosts.len(), 10);
+
+        // The binary evaluates from inside its own runtime
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let hosts = runtime.block_on(async {
+            let mut dir_reader = DirReader::new(Path::new("tests/slow_dir"));
+            dir_reader.register_probe(SlowProbe(Duration::from_millis(300)));
+            dir_reader.parse_all();
+            dir_reader.hosts.hosts.len()
+        });
+        assert_eq!(hosts, 10);
+
+        // Library callers may use a runtime that can't be blocked
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let hosts = runtime.block_on(async {
+            let mut dir_reader = DirReader::new(Path::new("tests/slow_dir"));
+            dir_reader.register_probe(SlowProbe(Duration::from_millis(300)));
+            dir_reader.parse_all();
+            dir_reader.hosts.hosts.len()
+        });
+        assert_eq!(hosts, 10);
+    }
+
+    #[test]
+    fn test_probe_deadline() {
+        use crate::parser::DirReader;
+        use std::path::Path;
+        use std::time::{Duration, Instant};
+        let mut dir_reader = DirReader::new(Path::new("tests/slow_dir"));
+        dir_reader.register_probe(SlowProbe(Duration::from_secs(5)));
+        dir_reader.probe_deadline = Duration::from_millis(100);
+        let start = Instant::now();
+        dir_reader.parse_all();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(dir_reader.hosts.hosts.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "ping")]
+    fn test_icmp_packets() {
+        use crate::ping::{checksum, echo_request, is_echo_reply};
+        let request = echo_request(false, 3);
+        assert_eq!(request[0], 8);
+        assert_eq!(&request[6..8], &[0, 3]);
+        // A packet containing its own checksum sums up to zero
+        assert_eq!(checksum(&request), 0);
+        assert_eq!(checksum(&[0x45, 0x00, 0x00, 0x1c]), !0x451c);
+
+        let mut reply = echo_request(false, 3);
+        reply[0] = 0;
+        assert!(is_echo_reply(&reply, false, 3));
+        assert!(!is_echo_reply(&reply, false, 4));
+        assert!(!is_echo_reply(&request, false, 3));
+        assert!(!is_echo_reply(&reply[..4], false, 3));
+
+        let mut reply_v6 = echo_request(true, 1);
+        assert_eq!(reply_v6[0], 128);
+        reply_v6[0] = 129;
+        assert!(is_echo_reply(&reply_v6, true, 1));
+    }
+
+    #[test]
+    #[cfg(feature = "ping")]
+    fn test_ping_localhost() {
+        use crate::ping::{ping, PingOptions};
+        use std::io::ErrorKind;
+        match ping("127.0.0.1".parse().unwrap(), &PingOptions::default()) {
+            Ok(reachable) => assert!(reachable),
+            // Unprivileged ICMP sockets are disabled for this user, nothing to test
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {},
+            Err(e) => panic!("Unexpected error while pinging localhost: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_assumptions() {
+        use crate::assume::{Assumption, Assumptions};
+        use crate::parser::DirReader;
+        use std::path::Path;
+        use std::str::FromStr;
+        let assumptions = ["ping:10.0.0.1=down", "file:/etc/vpn.up=true", "var:env_USER=ci", "vpn:office=up"]
+            .iter()
+            .map(|a| Assumption::from_str(a).unwrap())
+            .collect();
+        let mut dir_reader = DirReader::new(Path::new("tests/assume_dir"));
+        dir_reader.assume(Assumptions::new(assumptions));
+        dir_reader.parse_all();
+        let expected = vec![
+            Host::new("office".to_string(), "203.0.113.1".to_string()),
+            Host::new("vpn".to_string(), "10.8.0.1".to_string()),
+            Host::new("ci-runner".to_string(), "127.0.0.1".to_string()),
+            Host::new("unregistered-probe".to_string(), "10.9.0.1".to_string()),
+        ];
+        assert_eq!(dir_reader.hosts.hosts, expected);
+    }
+
+    #[test]
+    fn test_parse_assumption() {
+        use crate::assume::Assumption;
+        use std::str::FromStr;
+        assert_eq!(Assumption::from_str("file:/tmp/a=b=yes").unwrap(), Assumption::Probe {
+            probe: "file".to_string(),
+            argument: "/tmp/a=b".to_string(),
+            result: true,
+        });
+        assert_eq!(Assumption::from_str("var:greeting=a=b").unwrap(), Assumption::Var {
+            name: "greeting".to_string(),
+            value: "a=b".to_string(),
+        });
+        assert!(Assumption::from_str("ping:10.0.0.1=maybe").is_err());
+        assert!(Assumption::from_str("ping:10.0.0.1").is_err());
+        assert!(Assumption::from_str("nonsense").is_err());
+    }
+
+    #[test]
+    fn test_config_test_cases() {
+        use crate::testing::{find_cases, TestCase};
+        use std::path::Path;
+        let dir = Path::new("tests/testing_dir");
+        let cases = find_cases(Path::new("tests/testing_cases")).unwrap();
+        assert_eq!(cases.len(), 2);
+        for path in cases {
+            let case = TestCase::load(&path).unwrap();
+            let res = case.run(path.to_str().unwrap(), dir).unwrap();
+            assert!(res.passed, "{}: {:?}", res.name, res.diff);
+        }
+    }
+
+    #[test]
+    fn test_config_test_case_diff() {
+        use crate::testing::TestCase;
+        use std::path::Path;
+        let case: TestCase = toml::from_str(r#"
+            assume = ["ping:10.0.0.1=up"]
+            now = "2024-01-03T20:00:00"
+            expect = ["10.0.0.1 office", "0.0.0.0 distracting.example"]
+        "#).unwrap();
+        let res = case.run("failing", Path::new("tests/testing_dir")).unwrap();
+        assert!(!res.passed);
+        assert_eq!(res.diff, vec!["- 0.0.0.0\tdistracting.example".to_string()]);
+    }
+
+    #[test]
+    fn test_config_test_case_deterministic() {
+        use crate::testing::TestCase;
+        use std::path::Path;
+        let dir = Path::new("tests/testing_dir");
+        // Conditions depending on the machine must be assumed
+        let case: TestCase = toml::from_str(r#"
+            expect = []
+        "#).unwrap();
+        let err = case.run("unassumed", dir).unwrap_err();
+        assert!(err.contains("'ping 10.0.0.1'"), "{}", err);
+
+        // Without `now`, the test runs on Monday 2024-01-01 at midnight
+        let case: TestCase = toml::from_str(r#"
+            assume = ["ping:10.0.0.1=up"]
+            expect = ["10.0.0.1 office", "0.0.0.0 distracting.example"]
+        "#).unwrap();
+        let res = case.run("default_now", dir).unwrap();
+        assert!(!res.passed);
+        assert_eq!(res.diff, vec!["- 0.0.0.0\tdistracting.example".to_string()]);
+
+        assert!(case.run("missing", Path::new("tests/no_such_dir")).is_err());
+    }
+
+    #[test]
+    fn test_remove_across_files() {
+        use crate::parser::DirReader;
+        use std::path::Path;
+        let mut dir_reader = DirReader::new(Path::new("tests/remove_dir"));
+        dir_reader.parse_all();
+        let expected = vec![
+            Host::new("localhost".to_string(), "127.0.0.1".to_string()),
+            Host::new("printer".to_string(), "192.168.1.20".to_string()),
+            Host::new("new-server".to_string(), "10.0.0.5".to_string()),
+        ];
+        assert_eq!(dir_reader.hosts.hosts, expected);
+    }
+
+    fn parse_with_policy(policy: crate::conflicts::ConflictPolicy) -> crate::parser::DirReader {
+        use crate::parser::DirReader;
+        use std::path::Path;
+        let mut dir_reader = DirReader::new(Path::new("tests/conflict_dir"));
+        dir_reader.conflict_policy = policy;
+        dir_reader.parse_all();
+        dir_reader
+    }
+
+    #[test]
+    fn test_conflict_detection() {
+        use crate::conflicts::{ConflictKind, ConflictPolicy};
+        let dir_reader = parse_with_policy(ConflictPolicy::KeepBoth);
+        assert_eq!(dir_reader.hosts.hosts.len(), 6);
+        let conflicts = &dir_reader.conflicts;
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(conflicts[0].kind, ConflictKind::DifferentIp);
+        assert_eq!(conflicts[0].first.ip, "10.0.0.1");
+        assert_eq!(conflicts[0].second.ip, "10.0.0.9");
+        assert_eq!(conflicts[0].to_string(), "'server' is mapped to 10.0.0.1 (tests/conflict_dir/10-base.conf:3) and 10.0.0.9 (tests/conflict_dir/20-lab.conf:1)");
+        assert_eq!(conflicts[1].kind, ConflictKind::Duplicate);
+    }
+
+    #[test]
+    fn test_conflict_policies() {
+        use crate::conflicts::ConflictPolicy;
+        let entries = |policy| parse_with_policy(policy).hosts.hosts
+            .iter()
+            .map(|h| format!("{} {}", h.ip, h.hostname))
+            .collect::<Vec<_>>();
+        assert_eq!(entries(ConflictPolicy::FirstWins), vec!["127.0.0.1 localhost", "::1 localhost", "10.0.0.1 server", "10.0.0.2 nas"]);
+        assert_eq!(entries(ConflictPolicy::LastWins), vec!["127.0.0.1 localhost", "::1 localhost", "10.0.0.9 server", "10.0.0.2 nas"]);
+        // Errors are reported to the caller, the entries are left alone
+        let dir_reader = parse_with_policy(ConflictPolicy::Error);
+        assert_eq!(dir_reader.conflicts.len(), 2);
+        assert_eq!(dir_reader.hosts.hosts.len(), 6);
+    }
+
+    #[test]
+    fn test_write_provenance() {
+        use crate::parser::FileReader;
+        use crate::writer::{write_hosts_to_file, OutputOptions};
+        use std::path::Path;
+        use std::collections::HashMap;
+        let mut file = FileReader::new(Path::new("tests/std_file.conf"), HashMap::new());
+        file.parse_all();
+        let source = file.hosts.hosts[1].source.clone().unwrap();
+        assert_eq!(source.file_name(), "std_file.conf");
+        assert_eq!(source.line, 4);
+        assert_eq!(file.hosts.from_file("std_file.conf").count(), 4);
+
+        let output = std::env::temp_dir().join(format!("drophost-provenance-{}", std::process::id()));
+        let output = output.to_str().unwrap();
+        write_hosts_to_file(&file.hosts, output, &OutputOptions::default()).unwrap();
+        let expected = std::fs::read_to_string("tests/std_file_expected_out.conf").unwrap();
+        assert_eq!(std::fs::read_to_string(output).unwrap().trim_end(), expected.trim_end());
+
+        write_hosts_to_file(&file.hosts, output, &OutputOptions { provenance: true, ..Default::default() }).unwrap();
+        let written = std::fs::read_to_string(output).unwrap();
+        assert_eq!(written.lines().nth(2), Some("8.8.8.8\tgoog\t# from std_file.conf:4"));
+        std::fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn test_explain() {
+        use crate::explain::Outcome;
+        use crate::parser::DirReader;
+        use std::path::Path;
+        use std::collections::HashMap;
+        let vars = HashMap::from([("sys_hostname".to_string(), "laptop".to_string())]);
+        let mut dir_reader = DirReader::with_vars(Path::new("tests/explain_dir"), vars);
+        dir_reader.explain = Some("printer".to_string());
+        dir_reader.parse_all();
+        assert_eq!(dir_reader.hosts.find_hostname("printer").count(), 0);
+
+        let trace = &dir_reader.trace;
+        let outcomes = trace.iter().map(|e| (e.source.line, e.outcome.clone())).collect::<Vec<_>>();
+        assert_eq!(outcomes, vec![
+            (1, Outcome::Statement),
+            (4, Outcome::Skipped),
+            (7, Outcome::Skipped),
+            (1, Outcome::Removed),
+        ]);
+
+        // Skipped entries are explained by the conditions that failed
+        assert_eq!(trace[1].details, vec!["$office = printer"]);
+        assert_eq!(trace[1].decisions.len(), 2);
+        assert_eq!(trace[1].decisions[0].details, vec!["$sys_hostname = laptop"]);
+        assert!(trace[1].decisions[0].result);
+        assert!(!trace[1].decisions[1].result);
+        assert_eq!(trace[1].decisions[1].details, vec!["file /nonexistent/vpn.up is false"]);
+        assert_eq!(trace[2].decisions[2].details, vec!["var home is false"]);
+        assert!(trace[2].decisions[1].in_else);
+        assert_eq!(trace[2].to_string().lines().nth(3), Some("    because tests/explain_dir/10-office.conf:3: 'try file /nonexistent/vpn.up' is false, in the else branch"));
+    }
+
+    #[test]
+    fn test_query() {
+        use crate::types::{Host, Hosts};
+        let hosts = Hosts::from(vec![
+            Host::new("localhost".to_string(), "127.0.0.1".to_string()),
+            Host::new("localhost".to_string(), "::1".to_string()),
+            Host::new("ip6-localhost".to_string(), "0:0::1".to_string()),
+        ]);
+        let names = |ip| hosts.find_ip(ip).map(|h| h.hostname.as_str()).collect::<Vec<_>>();
+        assert_eq!(names("::1"), vec!["localhost", "ip6-localhost"]);
+        assert_eq!(names("127.0.0.1"), vec!["localhost"]);
+        assert!(names("10.0.0.1").is_empty());
+
+        let host = Host::new("nas".to_string(), "10.0.0.2".to_string())
+            .with_source(crate::types::Source { path: "hosts.d/20-lab.conf".into(), line: 3 });
+        assert_eq!(serde_json::to_string(&host).unwrap(),
+            r#"{"hostname":"nas","ip":"10.0.0.2","source":{"path":"hosts.d/20-lab.conf","line":3}}"#);
+    }
+
+    #[test]
+    fn test_atomic_write() {
+        use crate::writer::write_atomically;
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("drophost-atomic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hosts");
+
+        write_atomically(&path, b"127.0.0.1\tlocalhost\n").unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o644);
+
+        // The mode of an existing file is kept
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        write_atomically(&path, b"10.0.0.1\tnas\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "10.0.0.1\tnas\n");
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        // No temporary file is left behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // A stale temporary file from a crashed run doesn't block writing
+        let stale = dir.join(format!(".hosts.drophost-{}.tmp", std::process::id()));
+        std::fs::write(&stale, "").unwrap();
+        write_atomically(&path, b"10.0.0.2\tnas\n").unwrap();
+        std::fs::remove_file(&stale).unwrap();
+
+        // Concurrent writers don't get in each other's way
+        let writers = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || write_atomically(&path, b"10.0.0.3\tnas\n"))
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap().unwrap();
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // Errors are returned instead of panicking
+        assert!(write_atomically(&dir.join("missing/hosts"), b"").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_skip_unchanged_write() {
+        use crate::types::{Host, Hosts};
+        use crate::writer::{write_hosts_to_file, Changes, OutputOptions};
+        let path = std::env::temp_dir().join(format!("drophost-unchanged-{}", std::process::id()));
+        let output = path.to_str().unwrap();
+        let mut hosts = Hosts::from(vec![Host::new("localhost".to_string(), "127.0.0.1".to_string())]);
+
+        let changes = write_hosts_to_file(&hosts, output, &OutputOptions::default()).unwrap();
+        assert_eq!(changes, Some(Changes { added: vec!["127.0.0.1\tlocalhost".to_string()], removed: vec![] }));
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        assert_eq!(write_hosts_to_file(&hosts, output, &OutputOptions::default()).unwrap(), None);
+        assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), modified);
+
+        hosts.hosts[0].ip = "127.0.1.1".to_string();
+        let changes = write_hosts_to_file(&hosts, output, &OutputOptions::default()).unwrap().unwrap();
+        assert_eq!(changes.to_string(), "1 added, 1 removed");
+        assert_eq!(changes.removed, vec!["127.0.0.1\tlocalhost"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_managed_block() {
+        use crate::parser::FileReader;
+        use crate::types::{Host, Hosts};
+        use crate::writer::{splice_managed_block, write_hosts_to_file, OutputOptions};
+        use std::path::Path;
+        use std::collections::HashMap;
+        // Entries inside the block are drophost's own and are not read back
+        let mut file = FileReader::new(Path::new("tests/managed_hosts.conf"), HashMap::new());
+        file.parse_all();
+        let hostnames = file.hosts.hosts.iter().map(|h| h.hostname.as_str()).collect::<Vec<_>>();
+        assert_eq!(hostnames, vec!["localhost", "host.docker.internal", "vagrant"]);
+
+        let path = std::env::temp_dir().join(format!("drophost-managed-{}", std::process::id()));
+        std::fs::copy("tests/managed_hosts.conf", &path).unwrap();
+        let hosts = Hosts::from(vec![Host::new("nas".to_string(), "10.0.0.2".to_string())]);
+        let options = OutputOptions { managed_block: true, ..Default::default() };
+        write_hosts_to_file(&hosts, path.to_str().unwrap(), &options).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        let expected = std::fs::read_to_string("tests/managed_hosts.conf").unwrap().replace("10.0.0.1\told", "10.0.0.2\tnas");
+        assert_eq!(written, expected);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(splice_managed_block("127.0.0.1 localhost", "10.0.0.2\tnas\n").unwrap(),
+            "127.0.0.1 localhost\n# BEGIN drophost\n10.0.0.2\tnas\n# END drophost\n");
+        assert!(splice_managed_block("# BEGIN drophost\n10.0.0.1 old\n", "").is_err());
+        assert!(splice_managed_block("10.0.0.1 old\n# END drophost\n", "").is_err());
+
+        // Without an end marker, nothing after the begin marker is dropped
+        let path = std::env::temp_dir().join(format!("drophost-unterminated-{}", std::process::id()));
+        std::fs::write(&path, "127.0.0.1 localhost\n# BEGIN drophost\n10.0.0.1 nas\n").unwrap();
+        let mut file = FileReader::new(&path, HashMap::new());
+        file.parse_all();
+        let hostnames = file.hosts.hosts.iter().map(|h| h.hostname.as_str()).collect::<Vec<_>>();
+        assert_eq!(hostnames, vec!["localhost", "nas"]);
+        let options = OutputOptions { managed_block: true, ..Default::default() };
+        assert!(write_hosts_to_file(&hosts, path.to_str().unwrap(), &options).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "127.0.0.1 localhost\n# BEGIN drophost\n10.0.0.1 nas\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_entry_forms() {
+        use crate::parser::FileReader;
+        use std::path::Path;
+        use std::collections::HashMap;
+        let vars = HashMap::from([("name".to_string(), "files".to_string())]);
+        let mut file = FileReader::new(Path::new("tests/entry_forms.conf"), vars);
+        file.parse_all();
+        let entries = file.hosts.hosts.iter().map(|h| format!("{} {}", h.ip, h.hostname)).collect::<Vec<_>>();
+        assert_eq!(entries, vec![
+            "10.0.0.1 router", "10.0.0.2 nas", "10.0.0.3 printer#office", "10.0.0.4 files",
+            "10.0.0.5 web", "10.0.0.6 mail", "10.0.0.7 dns",
+        ]);
+        assert_eq!(file.hosts.tags_of(&file.hosts.hosts[4]), ["lan".to_string()]);
+        assert_eq!(file.hosts.tags_of(&file.hosts.hosts[6]), ["dns".to_string()]);
+    }
+
+    #[test]
+    fn test_grouped_output() {
+        use crate::parser::FileReader;
+        use crate::types::{Host, Hosts};
+        use crate::writer::{render, OutputOptions, SortOrder, MAX_LINE_LENGTH};
+        use std::path::Path;
+        use std::collections::HashMap;
+        let mut file = FileReader::new(Path::new("tests/group_file.conf"), HashMap::new());
+        file.parse_all();
+        assert_eq!(file.hosts.hosts.len(), 6);
+
+        let options = OutputOptions { group: true, sort: SortOrder::Ip, split_families: true, ..Default::default() };
+        assert_eq!(render(&file.hosts, &options), "# Generated automatically by drophost\n\
+            \n# IPv4\n10.0.0.1\trouter\n10.0.0.2\tnas storage backup\n127.0.0.1\tlocalhost\n\
+            \n# IPv6\n::1\tlocalhost\n");
+
+        let options = OutputOptions { group: true, provenance: true, ..Default::default() };
+        let rendered = render(&file.hosts, &options);
+        assert_eq!(rendered.lines().nth(1), Some("10.0.0.2\tnas storage backup\t# from group_file.conf:2, group_file.conf:3, group_file.conf:7"));
+
+        // Long lines are split
+        let hosts = Hosts::from((0..40).map(|i| Host::new(format!("host-{:02}.example.com", i), "10.0.0.1".to_string())).collect());
+        let rendered = render(&hosts, &OutputOptions { group: true, ..Default::default() });
+        let lines = rendered.lines().skip(1).collect::<Vec<_>>();
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.len() <= MAX_LINE_LENGTH && line.starts_with("10.0.0.1\t")));
+        assert_eq!(lines.iter().map(|line| line.split_whitespace().count() - 1).sum::<usize>(), 40);
+    }
+
+    #[test]
+    fn test_output_formats() {
+        use crate::types::{Host, Hosts};
+        use crate::writer::{Format, OutputOptions, write_hosts_to_file};
+        let hosts = Hosts::from(vec![
+            Host::new("nas".to_string(), "10.0.0.2".to_string()),
+            Host::new("storage".to_string(), "10.0.0.2".to_string()),
+            Host::new("nas".to_string(), "fd00::2".to_string()),
+            Host::new("broken".to_string(), "not-an-ip".to_string()),
+        ]);
+        let render = |format: Format| format.renderer().render(&hosts, &OutputOptions { format, ..Default::default() });
+
+        assert_eq!(render(Format::Dnsmasq).lines().skip(1).collect::<Vec<_>>(),
+            vec!["address=/nas/10.0.0.2", "address=/storage/10.0.0.2", "address=/nas/fd00::2"]);
+        assert_eq!(render(Format::Unbound).lines().skip(2).collect::<Vec<_>>(), vec![
+            "    local-data: \"nas. 300 IN A 10.0.0.2\"",
+            "    local-data-ptr: \"10.0.0.2 300 nas.\"",
+            "    local-data: \"storage. 300 IN A 10.0.0.2\"",
+            "    local-data: \"nas. 300 IN AAAA fd00::2\"",
+            "    local-data-ptr: \"fd00::2 300 nas.\"",
+        ]);
+        assert!(render(Format::Coredns).contains("hosts {\n    10.0.0.2\tnas\n"));
+        assert_eq!(render(Format::Bind).lines().nth(1), Some("nas\t300\tIN\tA\t10.0.0.2"));
+
+        let json = render(Format::Json);
+        let renderer = Format::Json.renderer();
+        assert_eq!(renderer.entries(&json).len(), 4);
+        assert_eq!(renderer.entries(&json)[0], "10.0.0.2\tnas");
+
+        // Zone files and JSON can't hold the markers of managed block mode
+        let path = std::env::temp_dir().join(format!("drophost-formats-{}", std::process::id()));
+        let options = OutputOptions { format: Format::Json, managed_block: true, ..Default::default() };
+        assert!(write_hosts_to_file(&hosts, path.to_str().unwrap(), &options).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_bind_zone() {
+        use crate::types::{Host, Hosts};
+        use crate::writer::{Format, OutputOptions};
+        use std::net::IpAddr;
+        let hosts = Hosts::from(vec![
+            Host::new("nas".to_string(), "10.0.0.2".to_string()),
+            Host::new("printer.lan".to_string(), "10.0.0.3".to_string()),
+            Host::new("lan.".to_string(), "10.0.0.1".to_string()),
+            Host::new("nas".to_string(), "fd00::2".to_string()),
+            Host::new("mail.example.com".to_string(), "10.0.0.4".to_string()),
+        ]);
+        let options = OutputOptions { format: Format::Bind, zone: Some("lan".to_string()), ..Default::default() };
+        let zone = Format::Bind.renderer().render(&hosts, &options);
+
+        // Checks the parts of RFC 1035 zone files that name servers require
+        let lines = zone.lines().filter(|line| !line.starts_with(';')).collect::<Vec<_>>();
+        assert_eq!(lines[0], "$ORIGIN lan.");
+        assert!(lines[1].strip_prefix("$TTL ").unwrap().parse::<u32>().is_ok());
+        let mut records = vec![];
+        for line in &lines[2..] {
+            let (rdata, comment) = line.split_once('(').map(|(start, rest)| (start, rest.trim_end_matches(" )"))).unwrap_or((line, ""));
+            let fields = rdata.split_whitespace().chain(comment.split_whitespace()).collect::<Vec<_>>();
+            let (name, fields) = fields.split_first().unwrap();
+            let fields = match fields[0].parse::<u32>() {
+                Ok(_) => &fields[1..],
+                Err(_) => fields,
+            };
+            assert_eq!(fields[0], "IN", "{}", line);
+            assert!(!name.ends_with('.'), "names are relative to the origin: {}", line);
+            match fields[1] {
+                "SOA" => {
+                    assert_eq!(fields.len(), 9, "{}", line);
+                    assert!(fields[4..].iter().all(|field| field.parse::<u32>().is_ok()), "{}", line);
+                },
+                "NS" => assert!(fields[2].ends_with('.')),
+                "A" => assert!(matches!(fields[2].parse(), Ok(IpAddr::V4(_)))),
+                "AAAA" => assert!(matches!(fields[2].parse(), Ok(IpAddr::V6(_)))),
+                kind => panic!("unexpected record type {}", kind),
+            }
+            records.push(format!("{} {} {}", name, fields[1], fields.last().unwrap()));
+        }
+        assert_eq!(records[0], "@ SOA 300");
+        assert_eq!(records[1], "@ NS localhost.");
+        // Names outside of the zone are left out
+        assert_eq!(records[2..], ["nas A 10.0.0.2", "printer A 10.0.0.3", "@ A 10.0.0.1", "nas AAAA fd00::2"]);
+    }
+
+    #[test]
+    fn test_targets() {
+        use crate::parser::FileReader;
+        use crate::targets::Target;
+        use crate::writer::{Format, OutputOptions, SortOrder};
+        use std::path::Path;
+        use std::str::FromStr;
+        use std::collections::HashMap;
+        let mut file = FileReader::new(Path::new("tests/tags_file.conf"), HashMap::new());
+        file.parse_all();
+        assert_eq!(file.hosts.hosts.len(), 4);
+        assert_eq!(file.hosts.hosts[1].hostname, "storage");
+        assert_eq!(file.hosts.tags_of(&file.hosts.hosts[1]), ["lan", "backup"]);
+
+        let target = Target::from_str("/etc/dnsmasq.d/lan.conf,format=dnsmasq,tag=lan").unwrap();
+        assert_eq!(target.path, Path::new("/etc/dnsmasq.d/lan.conf"));
+        assert_eq!(target.format, Format::Dnsmasq);
+        let hostnames = |target: &Target| target.filter.apply(&file.hosts).hosts.iter().map(|h| h.hostname.clone()).collect::<Vec<_>>();
+        assert_eq!(hostnames(&target), vec!["nas", "storage", "printer"]);
+        assert_eq!(hostnames(&Target::from_str("/etc/hosts").unwrap()).len(), 4);
+
+        assert!(Target::from_str("/etc/hosts,format=unknown").is_err());
+        assert_eq!(Target::from_str("/etc/bind/db.lan,format=bind,zone=lan").unwrap().zone, Some("lan".to_string()));
+
+        // Options that a target doesn't set come from the command line
+        let defaults = OutputOptions { provenance: true, group: true, ..Default::default() };
+        let target = Target::from_str("/etc/hosts,managed-block,group=false,sort=ip").unwrap();
+        let options = target.output_options(&defaults);
+        assert!(options.managed_block && options.provenance && !options.group);
+        assert_eq!(options.sort, SortOrder::Ip);
+        assert!(Target::from_str("/etc/hosts,group=maybe").is_err());
+        assert!(Target::from_str("/etc/hosts,sort=size").is_err());
+        assert!(Target::from_str("/etc/hosts,tags").is_err());
+        assert!(Target::from_str(",tag=lan").is_err());
+    }
+
+    #[test]
+    fn test_config_file() {
+        use crate::config::Config;
+        use crate::conflicts::ConflictPolicy;
+        use crate::writer::Format;
+        use std::path::Path;
+        use std::time::Duration;
+        let config = Config::load(Path::new("tests/drophost.toml"), true).unwrap();
+        assert_eq!(config.root.as_deref(), Some(Path::new("/srv/lab/etc")));
+        assert_eq!(config.probe_deadline.map(Duration::from), Some(Duration::from_secs(2)));
+        assert_eq!(config.conflicts, Some(ConflictPolicy::LastWins));
+        assert_eq!(config.assume.unwrap().len(), 1);
+        assert_eq!(config.log_level, Some(log::LevelFilter::Debug));
+        let targets = config.target.unwrap();
+        assert_eq!(targets[0].format, Format::Hosts);
+        assert_eq!(targets[0].managed_block, Some(true));
+        assert_eq!(targets[0].sort, Some(crate::writer::SortOrder::Ip));
+        assert_eq!(targets[1].format, Format::Dnsmasq);
+        assert_eq!(targets[1].filter.tags, vec!["lan"]);
+
+        // The default config file is optional, one given explicitly isn't
+        assert!(Config::load(Path::new("tests/missing.toml"), false).unwrap().root.is_none());
+        assert!(Config::load(Path::new("tests/missing.toml"), true).is_err());
+        assert!(toml::from_str::<Config>("dry-run = true").is_err());
+        assert!(toml::from_str::<Config>("sort = \"size\"").is_err());
+    }
+
+    #[test]
+    fn test_layered_dirs() {
+        use crate::parser::{is_masked, DirReader};
+        use std::path::Path;
+        use std::collections::HashMap;
+        let mut dir_reader = DirReader::with_vars(Path::new("tests/layered_dir/etc"), HashMap::new());
+        dir_reader.add_fallback_dir(Path::new("tests/layered_dir/run"));
+        dir_reader.add_fallback_dir(Path::new("tests/layered_dir/lib"));
+        dir_reader.add_fallback_dir(Path::new("tests/layered_dir/missing"));
+        dir_reader.scan();
+        let files = dir_reader.files.iter().map(|f| f.to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(files, vec![
+            "tests/layered_dir/lib/10-defaults.conf",
+            "tests/layered_dir/run/15-runtime.conf",
+            "tests/layered_dir/etc/20-nas.conf",
+            "tests/layered_dir/etc/30-printer.conf",
+        ]);
+        assert!(is_masked(Path::new("tests/layered_dir/etc/30-printer.conf")));
+
+        dir_reader.parse_all();
+        let hostnames = dir_reader.hosts.hosts.iter().map(|h| h.hostname.as_str()).collect::<Vec<_>>();
+        assert_eq!(hostnames, vec!["vendor-default", "runtime", "admin-nas"]);
+    }
+
+    #[test]
+    fn test_ignored_files() {
+        use crate::parser::{is_config_file, DirReader, DEFAULT_PATTERN};
+        use glob::Pattern;
+        use std::path::Path;
+        use std::collections::HashMap;
+        let mut dir_reader = DirReader::with_vars(Path::new("tests/junk_dir"), HashMap::new());
+        dir_reader.scan();
+        assert_eq!(dir_reader.files, vec![Path::new("tests/junk_dir/10-a.conf")]);
+
+        // Junk is skipped whatever the pattern is
+        dir_reader.set_pattern(Pattern::new("*").unwrap());
+        assert!(!dir_reader.scanned);
+        dir_reader.scan();
+        assert_eq!(dir_reader.files, vec![Path::new("tests/junk_dir/10-a.conf"), Path::new("tests/junk_dir/notes.txt")]);
+
+        let pattern = Pattern::new(DEFAULT_PATTERN).unwrap();
+        assert!(is_config_file(Path::new("/etc/hosts.d/10-lan.conf"), &pattern));
+        assert!(!is_config_file(Path::new("/etc/hosts.d/.10-lan.conf.swp"), &pattern));
+        assert!(!is_config_file(Path::new("/etc/hosts.d/10-lan.conf.pacnew"), &pattern));
+        assert!(!is_config_file(Path::new("/etc/hosts.d/4913"), &pattern));
+    }
+
+    #[test]
+    fn test_nested_dirs() {
+        use crate::parser::DirReader;
+        use crate::utils::{natural_cmp, natural_path_cmp};
+        use std::cmp::Ordering;
+        use std::path::Path;
+        use std::collections::HashMap;
+        assert_eq!(natural_cmp("9-foo.conf", "10-bar.conf"), Ordering::Less);
+        assert_eq!(natural_cmp("a2", "a10"), Ordering::Less);
+        assert_eq!(natural_cmp("010-x", "10-x"), Ordering::Less);
+        assert_eq!(natural_path_cmp(Path::new("teams/z.conf"), Path::new("teams-old.conf")), Ordering::Less);
+
+        let mut dir_reader = DirReader::with_vars(Path::new("tests/nested_dir"), HashMap::new());
+        dir_reader.parse_all();
+        let hostnames = dir_reader.hosts.hosts.iter().map(|h| h.hostname.as_str()).collect::<Vec<_>>();
+        // A priority directive only moves a file within its own directory
+        assert_eq!(hostnames, vec!["base", "last", "db", "api", "first", "web"]);
+    }
+
+    #[test]
+    fn test_file_priorities() {
+        use crate::parser::{parse_priority, DirReader, Priority};
+        use std::path::Path;
+        use std::collections::HashMap;
+        let mut dir_reader = DirReader::with_vars(Path::new("tests/priority_dir"), HashMap::new());
+        dir_reader.scan();
+        let names = dir_reader.files.iter().map(|f| f.file_name().unwrap().to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["zz-important.conf", "9-early.conf", "10-base.conf", "20-late.conf", "30-invalid.conf", "vendor.conf"]);
+        assert_eq!(dir_reader.priorities[Path::new("tests/priority_dir/zz-important.conf")], Priority::Directive(5));
+        assert_eq!(dir_reader.priorities[Path::new("tests/priority_dir/20-late.conf")], Priority::Name(20));
+        assert_eq!(dir_reader.priorities[Path::new("tests/priority_dir/vendor.conf")].to_string(), "-");
+        assert_eq!(dir_reader.priorities[Path::new("tests/priority_dir/30-invalid.conf")], Priority::Name(30));
+        assert_eq!(parse_priority("priority\t-3"), Some(Ok(-3)));
+        assert!(parse_priority("priority abc").unwrap().is_err());
+        assert!(parse_priority("priority 1 2").unwrap().is_err());
+        assert_eq!(parse_priority("10.0.0.1 priority"), None);
+
+        // A 'priority' line after an entry is an error and isn't read as a host, invalid
+        // priorities are only reported when parsing, which stops at them like other errors
+        dir_reader.parse_all();
+        let hostnames = dir_reader.hosts.hosts.iter().map(|h| h.hostname.as_str()).collect::<Vec<_>>();
+        assert_eq!(hostnames, vec!["important", "early", "base", "late", "vendor"]);
+    }
+
+    // Removes a temporary directory when the test ends, even if it fails
+    struct TempDir(std::path::PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_disable_files() {
+        use crate::parser::{is_config_file, is_masked, DirReader};
+        use glob::Pattern;
+        use std::path::Path;
+        use std::collections::HashMap;
+        let dir = TempDir(std::env::temp_dir().join(format!("drophost-disable-{}", std::process::id())));
+        let (etc, lib) = (dir.0.join("etc"), dir.0.join("lib"));
+        std::fs::create_dir_all(&etc).unwrap();
+        std::fs::create_dir_all(&lib).unwrap();
+        std::fs::copy("tests/layered_dir/etc/20-nas.conf", etc.join("20-nas.conf")).unwrap();
+        for name in ["10-defaults.conf", "20-nas.conf"] {
+            std::fs::copy(Path::new("tests/layered_dir/lib").join(name), lib.join(name)).unwrap();
+        }
+        // A reader is only parsed once, like on every run
+        let hostnames = || {
+            let mut dir_reader = DirReader::with_vars(&etc, HashMap::new());
+            dir_reader.add_fallback_dir(&lib);
+            dir_reader.parse_all();
+            dir_reader.hosts.hosts.iter().map(|h| h.hostname.clone()).collect::<Vec<_>>()
+        };
+        let mut dir_reader = DirReader::with_vars(&etc, HashMap::new());
+        dir_reader.add_fallback_dir(&lib);
+        assert_eq!(hostnames(), vec!["vendor-default", "admin-nas"]);
+
+        // Disabling the admin's file lets the vendor one be read again
+        assert_eq!(dir_reader.disable("20-nas").unwrap(), etc.join("20-nas.conf.disabled"));
+        assert_eq!(dir_reader.disabled, vec![etc.join("20-nas.conf.disabled")]);
+        assert_eq!(hostnames(), vec!["vendor-default", "vendor-nas"]);
+        assert!(!is_config_file(&etc.join("20-nas.conf.disabled"), &Pattern::new("*").unwrap()));
+
+        // Vendor files are masked instead of renamed
+        assert_eq!(dir_reader.disable("10-defaults.conf").unwrap(), etc.join("10-defaults.conf"));
+        assert!(is_masked(&etc.join("10-defaults.conf")) && lib.join("10-defaults.conf").exists());
+        assert_eq!(hostnames(), vec!["vendor-nas"]);
+        assert!(dir_reader.disable("10-defaults").is_err());
+        assert!(dir_reader.disable("missing").is_err());
+
+        assert_eq!(dir_reader.enable("10-defaults").unwrap(), lib.join("10-defaults.conf"));
+        assert_eq!(dir_reader.enable("20-nas.conf").unwrap(), etc.join("20-nas.conf"));
+        assert!(dir_reader.disabled.is_empty() && dir_reader.enable("20-nas").is_err());
+        assert_eq!(hostnames(), vec!["vendor-default", "admin-nas"]);
+    }
+}
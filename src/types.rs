@@ -1,14 +1,56 @@
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::PathBuf;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+use serde::Serialize;
+
+/// Where an entry was defined
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct Source {
+    pub path: PathBuf,
+    pub line: usize,
+}
+
+impl Source {
+    /// Name of the file without its directory, e.g. '30-lab.conf'
+    pub fn file_name(&self) -> String {
+        match self.path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => self.path.display().to_string(),
+        }
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.path.display(), self.line)
+    }
+}
+
+#[derive(Debug, Clone, Eq, Serialize)]
 pub struct Host {
     pub hostname: String,
     pub ip: String,
+    pub source: Option<Source>,
+}
+
+// Two entries are the same if they map the same hostname to the same IP,
+// wherever they come from
+impl PartialEq for Host {
+    fn eq(&self, other: &Self) -> bool {
+        self.hostname == other.hostname && self.ip == other.ip
+    }
 }
 
 impl Host {
     pub fn new(hostname: String, ip: String) -> Self {
-        Host { hostname, ip }
+        Host { hostname, ip, source: None }
+    }
+
+    pub fn with_source(mut self, source: Source) -> Self {
+        self.source = Some(source);
+        self
     }
 
     pub fn parse_entry(entry: &str) -> Option<Self> {
@@ -21,29 +63,35 @@ impl Host {
         Some(Host::new(hostname.to_string(), ip.to_string()))
     }
 
-    pub fn to_string(&self) -> String {
-        format!("{}\t{}", self.ip, self.hostname)
-    }
 }
 
 impl Display for Host {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.to_string())
+        write!(f, "{}\t{}", self.ip, self.hostname)
     }
 }
 
-#[derive(Debug)]
+/// Entries to take out of the hosts defined so far
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Removal {
+    Hostname(String),
+    Ip(String),
+}
+
+#[derive(Debug, Default)]
 pub struct Hosts {
     pub hosts: Vec<Host>,
+    /// Tags given with '@' on the lines entries were defined on, used to filter output targets
+    pub tags: HashMap<Source, Vec<String>>,
 }
 
 impl Hosts {
     pub fn new() -> Self {
-        Hosts { hosts: vec![] }
+        Hosts { hosts: vec![], tags: HashMap::new() }
     }
 
     pub fn from(entries: Vec<Host>) -> Self {
-        Hosts { hosts: entries }
+        Hosts { hosts: entries, tags: HashMap::new() }
     }
 
     pub fn add(&mut self, host: Host) {
@@ -52,17 +100,63 @@ impl Hosts {
 
     pub fn extend(&mut self, hosts: &Hosts) {
         self.hosts.extend(hosts.hosts.clone());
+        self.tags.extend(hosts.tags.clone());
+    }
+
+    /// Tags every entry defined on the line
+    pub fn tag(&mut self, source: Source, tags: Vec<String>) {
+        if !tags.is_empty() {
+            self.tags.insert(source, tags);
+        }
+    }
+
+    /// Tags of the entry, given on the line it was defined on
+    pub fn tags_of(&self, host: &Host) -> &[String] {
+        host.source.as_ref().and_then(|source| self.tags.get(source)).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Every entry for the hostname, in the order they were defined
+    pub fn find_hostname<'a>(&'a self, hostname: &'a str) -> impl Iterator<Item = &'a Host> {
+        self.hosts.iter().filter(move |h| h.hostname == hostname)
+    }
+
+    /// Every entry for the IP address. Addresses are compared by value when
+    /// they can be parsed, so '::1' matches '0:0::1'
+    pub fn find_ip<'a>(&'a self, ip: &'a str) -> impl Iterator<Item = &'a Host> {
+        let parsed = ip.parse::<IpAddr>().ok();
+        self.hosts.iter().filter(move |h| match (parsed, h.ip.parse::<IpAddr>()) {
+            (Some(ip), Ok(host_ip)) => ip == host_ip,
+            _ => h.ip == ip,
+        })
+    }
+
+    /// Every entry defined in the given file, e.g. '30-lab.conf'
+    pub fn from_file<'a>(&'a self, file_name: &'a str) -> impl Iterator<Item = &'a Host> {
+        self.hosts.iter().filter(move |h| h.source.as_ref().is_some_and(|s| s.file_name() == file_name))
     }
 
     pub fn remove(&mut self, host: &Host) {
         self.hosts.retain(|h| h != host);
     }
 
-    pub fn to_string(&self) -> String {
-        self.hosts
+    /// Removes every entry matching the removal, returns how many were removed
+    pub fn apply_removal(&mut self, removal: &Removal) -> usize {
+        let before = self.hosts.len();
+        match removal {
+            Removal::Hostname(hostname) => self.hosts.retain(|h| &h.hostname != hostname),
+            Removal::Ip(ip) => self.hosts.retain(|h| &h.ip != ip),
+        }
+        before - self.hosts.len()
+    }
+}
+
+impl Display for Hosts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lines = self.hosts
             .iter()
             .map(|h| h.to_string())
             .collect::<Vec<String>>()
-            .join("\n")
+            .join("\n");
+        write!(f, "{}", lines)
     }
 }
\ No newline at end of file
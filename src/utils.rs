@@ -1,4 +1,6 @@
+use std::cmp::Ordering;
 use std::fmt::Debug;
+use std::path::Path;
 use std::{error::Error, fmt::Display};
 use log::*;
 
@@ -108,4 +110,57 @@ pub fn unwrap_result_or_err<T: Debug, E>(result: Result<T, E>, message: &str, fa
                 }
             }
         }
-    }
\ No newline at end of file
+    }
+
+/// Compares strings the way people would, with runs of digits compared by their
+/// value, so that '9-foo.conf' comes before '10-bar.conf'
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a_chars, mut b_chars) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => break,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let take_number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut digits = String::new();
+                    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+                        digits.push(c);
+                    }
+                    digits.trim_start_matches('0').to_owned()
+                };
+                let (x, y) = (take_number(&mut a_chars), take_number(&mut b_chars));
+                let ordering = x.len().cmp(&y.len()).then_with(|| x.cmp(&y));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            },
+            (Some(x), Some(y)) => {
+                let ordering = x.cmp(y);
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a_chars.next();
+                b_chars.next();
+            },
+        }
+    }
+    // Keep the order total for names like '01' and '1'
+    a.cmp(b)
+}
+
+/// Compares paths component by component with `natural_cmp`, so that every file
+/// of a directory is next to each other
+pub fn natural_path_cmp(a: &Path, b: &Path) -> Ordering {
+    let components = |path: &Path| path.components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let (a, b) = (components(a), components(b));
+    for (x, y) in a.iter().zip(&b) {
+        let ordering = natural_cmp(x, y);
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
@@ -1,14 +1,528 @@
-use std::io::Write;
+use log::*;
 
-use crate::{types::Hosts, utils::unwrap_result_or_err};
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn write_hosts_to_file(hosts: &Hosts, path: &str) {
-    let mut file = std::fs::File::create(path).unwrap();
-    let res = file.write_all("# Generated automatically by drophost\n".as_bytes());
-    let _ = unwrap_result_or_err(res,
-                                "Could not write to output file!", true);
-    for host in &hosts.hosts {
-        file.write_all(host.to_string().as_bytes()).unwrap();
-        file.write_all("\n".as_bytes()).unwrap();
+use crate::types::{Host, Hosts, Source};
+
+// Mode of a new hosts file, it has to be readable by every user
+const DEFAULT_MODE: u32 = 0o644;
+
+/// Lines delimiting the part of the hosts file owned by drophost in managed block mode
+pub const BEGIN_MARKER: &str = "# BEGIN drophost";
+pub const END_MARKER: &str = "# END drophost";
+
+// TTL of the records written in DNS server formats
+pub const DNS_TTL: u32 = 300;
+
+// Grouped lines are split before reaching this length, as some resolvers
+// ignore the end of longer lines
+pub const MAX_LINE_LENGTH: usize = 255;
+
+/// Order of the entries in the hosts file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    /// The order in which the entries were read
+    #[default]
+    Source,
+    /// By IP address, entries for the same IP keep the order in which they were read
+    Ip,
+}
+
+/// Format of the generated file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// /etc/hosts, also read by dnsmasq's addn-hosts option
+    #[default]
+    Hosts,
+    /// dnsmasq 'address=' options
+    Dnsmasq,
+    /// unbound 'local-data' records, with PTR records
+    Unbound,
+    /// CoreDNS 'hosts' plugin block
+    Coredns,
+    /// BIND zone file, or records to be included in a zone if no zone is set
+    Bind,
+    /// JSON array of entries with their provenance
+    Json,
+}
+
+impl Format {
+    pub fn renderer(&self) -> Box<dyn Renderer> {
+        match self {
+            Format::Hosts => Box::new(HostsRenderer),
+            Format::Dnsmasq => Box::new(DnsmasqRenderer),
+            Format::Unbound => Box::new(UnboundRenderer),
+            Format::Coredns => Box::new(CorednsRenderer),
+            Format::Bind => Box::new(BindRenderer),
+            Format::Json => Box::new(JsonRenderer),
+        }
+    }
+}
+
+/// Turns the evaluated entries into the contents of an output file
+pub trait Renderer {
+    fn render(&self, hosts: &Hosts, options: &OutputOptions) -> String;
+
+    /// Entries of a rendered file in a normalized form, used to log what changed
+    fn entries(&self, contents: &str) -> Vec<String> {
+        contents.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with(';'))
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Whether the format has '#' comments, which the markers of managed block mode need
+    fn managed_block(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    pub format: Format,
+    /// Add the file and line each entry comes from as a trailing comment
+    pub provenance: bool,
+    /// Only replace the lines between `BEGIN_MARKER` and `END_MARKER`, keeping the rest of the file
+    pub managed_block: bool,
+    /// Write every hostname of an IP on the same line
+    pub group: bool,
+    pub sort: SortOrder,
+    /// Write IPv4 and IPv6 entries in separate sections
+    pub split_families: bool,
+    /// Origin of the zone written by the bind format, as in 'lan'
+    pub zone: Option<String>,
+}
+
+// One line of the hosts file
+struct Line<'a> {
+    ip: &'a str,
+    hostnames: Vec<&'a str>,
+    sources: Vec<&'a Source>,
+}
+
+impl Line<'_> {
+    fn length(&self) -> usize {
+        self.ip.len() + self.hostnames.iter().map(|hostname| hostname.len() + 1).sum::<usize>()
+    }
+
+    fn render(&self, provenance: bool) -> String {
+        let mut line = format!("{}\t{}", self.ip, self.hostnames.join(" "));
+        if provenance && !self.sources.is_empty() {
+            let sources = self.sources.iter()
+                .map(|source| format!("{}:{}", source.file_name(), source.line))
+                .collect::<Vec<_>>();
+            line += &format!("\t# from {}", sources.join(", "));
+        }
+        line
+    }
+}
+
+fn lines<'a>(hosts: &[&'a Host], group: bool) -> Vec<Line<'a>> {
+    let mut lines: Vec<Line> = vec![];
+    for host in hosts {
+        // Duplicates are already reported as conflicts
+        if group && lines.iter().any(|line| line.ip == host.ip && line.hostnames.contains(&host.hostname.as_str())) {
+            continue;
+        }
+
+        // Start a new line for the same IP when the last one would get too long
+        let index = match lines.iter().rposition(|line| group && line.ip == host.ip) {
+            Some(index) if lines[index].length() + 1 + host.hostname.len() <= MAX_LINE_LENGTH => index,
+            _ => {
+                lines.push(Line { ip: &host.ip, hostnames: vec![], sources: vec![] });
+                lines.len() - 1
+            }
+        };
+        let line = &mut lines[index];
+        line.hostnames.push(&host.hostname);
+        if let Some(source) = &host.source {
+            if !line.sources.contains(&source) {
+                line.sources.push(source);
+            }
+        }
+    }
+    lines
+}
+
+// Entries in the order set by the options
+fn ordered<'a>(hosts: &'a Hosts, options: &OutputOptions) -> Vec<&'a Host> {
+    let mut entries = hosts.hosts.iter().collect::<Vec<_>>();
+    if options.sort == SortOrder::Ip {
+        // Unparsable IPs go last, sort_by_key is stable so the source order is kept for ties
+        entries.sort_by_key(|host| host.ip.parse::<IpAddr>().map_err(|_| host.ip.clone()));
+    }
+    entries
+}
+
+// Entries that can be written as DNS records, along with their parsed IP
+fn records<'a>(hosts: &'a Hosts, options: &OutputOptions) -> Vec<(&'a Host, IpAddr)> {
+    ordered(hosts, options).into_iter()
+        .filter_map(|host| match host.ip.parse() {
+            Ok(ip) => Some((host, ip)),
+            Err(_) => {
+                warn!("Skipping '{}', '{}' is not a valid IP address", host.hostname, host.ip);
+                None
+            }
+        })
+        .collect()
+}
+
+fn absolute(hostname: &str) -> String {
+    match hostname.ends_with('.') {
+        true => hostname.to_owned(),
+        false => format!("{}.", hostname),
+    }
+}
+
+// Name of a record of the zone, single label names such as 'nas' are taken as part of it
+fn relative_to(hostname: &str, origin: &str) -> Option<String> {
+    let name = absolute(hostname);
+    if name == origin {
+        return Some("@".to_owned());
+    }
+    match name.strip_suffix(origin).and_then(|name| name.strip_suffix('.')) {
+        Some(name) if !name.is_empty() => Some(name.to_owned()),
+        _ if !hostname.contains('.') => Some(hostname.to_owned()),
+        _ => None,
+    }
+}
+
+fn provenance(host: &Host, comment: &str, options: &OutputOptions) -> String {
+    match (options.provenance, &host.source) {
+        (true, Some(source)) => format!("{} from {}:{}\n", comment, source.file_name(), source.line),
+        _ => String::new(),
+    }
+}
+
+pub struct HostsRenderer;
+
+impl Renderer for HostsRenderer {
+    fn render(&self, hosts: &Hosts, options: &OutputOptions) -> String {
+        render(hosts, options)
+    }
+
+    fn entries(&self, contents: &str) -> Vec<String> {
+        entries(contents)
+    }
+}
+
+pub struct DnsmasqRenderer;
+
+impl Renderer for DnsmasqRenderer {
+    fn render(&self, hosts: &Hosts, options: &OutputOptions) -> String {
+        let mut contents = String::from("# Generated automatically by drophost\n");
+        for (host, ip) in records(hosts, options) {
+            contents += &provenance(host, "#", options);
+            contents += &format!("address=/{}/{}\n", host.hostname, ip);
+        }
+        contents
+    }
+}
+
+pub struct UnboundRenderer;
+
+impl Renderer for UnboundRenderer {
+    fn render(&self, hosts: &Hosts, options: &OutputOptions) -> String {
+        let mut contents = String::from("# Generated automatically by drophost\nserver:\n");
+        let mut reversed = vec![];
+        for (host, ip) in records(hosts, options) {
+            let kind = if ip.is_ipv4() { "A" } else { "AAAA" };
+            contents += &provenance(host, "    #", options);
+            contents += &format!("    local-data: \"{} {} IN {} {}\"\n", absolute(&host.hostname), DNS_TTL, kind, ip);
+            // An address only gets one PTR record, for the first hostname it's mapped to
+            if !reversed.contains(&ip) {
+                reversed.push(ip);
+                contents += &format!("    local-data-ptr: \"{} {} {}\"\n", ip, DNS_TTL, absolute(&host.hostname));
+            }
+        }
+        contents
+    }
+}
+
+pub struct CorednsRenderer;
+
+impl Renderer for CorednsRenderer {
+    fn render(&self, hosts: &Hosts, options: &OutputOptions) -> String {
+        let mut contents = String::from("# Generated automatically by drophost\nhosts {\n");
+        for line in lines(&ordered(hosts, options), options.group) {
+            contents += &format!("    {}\n", line.render(options.provenance));
+        }
+        contents += "    fallthrough\n}\n";
+        contents
+    }
+}
+
+/// Writes a whole zone if `OutputOptions::zone` is set, with names relative to its origin.
+/// Otherwise, writes records to be included in a zone with '$INCLUDE', with names relative
+/// to the origin of the including zone.
+pub struct BindRenderer;
+
+impl Renderer for BindRenderer {
+    fn render(&self, hosts: &Hosts, options: &OutputOptions) -> String {
+        let origin = options.zone.as_deref().map(absolute);
+        let mut contents = "; Generated automatically by drophost\n".to_owned();
+        if let Some(origin) = &origin {
+            // The zone is only served by this server, so the serial never needs to change
+            contents += &format!("$ORIGIN {}\n$TTL {}\n", origin, DNS_TTL);
+            contents += &format!("@\tIN\tSOA\tlocalhost. hostmaster.localhost. ( 1 3600 600 86400 {} )\n", DNS_TTL);
+            contents += "@\tIN\tNS\tlocalhost.\n";
+        }
+        for (host, ip) in records(hosts, options) {
+            let name = match &origin {
+                Some(origin) => match relative_to(&host.hostname, origin) {
+                    Some(name) => name,
+                    None => {
+                        warn!("Skipping '{}', it is outside of the zone '{}'", host.hostname, origin);
+                        continue;
+                    }
+                },
+                None => host.hostname.clone(),
+            };
+            let kind = if ip.is_ipv4() { "A" } else { "AAAA" };
+            contents += &provenance(host, ";", options);
+            contents += &format!("{}\t{}\tIN\t{}\t{}\n", name, DNS_TTL, kind, ip);
+        }
+        contents
+    }
+
+    fn managed_block(&self) -> bool {
+        false
+    }
+}
+
+pub struct JsonRenderer;
+
+impl Renderer for JsonRenderer {
+    fn render(&self, hosts: &Hosts, options: &OutputOptions) -> String {
+        let entries = ordered(hosts, options);
+        serde_json::to_string_pretty(&entries).expect("This should never happen! (JsonRenderer::render)") + "\n"
+    }
+
+    fn entries(&self, contents: &str) -> Vec<String> {
+        let entries = serde_json::from_str::<Vec<serde_json::Value>>(contents).unwrap_or_default();
+        entries.iter()
+            .map(|entry| format!("{}\t{}", entry["ip"].as_str().unwrap_or_default(), entry["hostname"].as_str().unwrap_or_default()))
+            .collect()
+    }
+
+    fn managed_block(&self) -> bool {
+        false
     }
-}
\ No newline at end of file
+}
+
+/// Renders the hosts file, including the header
+pub fn render(hosts: &Hosts, options: &OutputOptions) -> String {
+    let entries = ordered(hosts, options);
+
+    let sections = if options.split_families {
+        let (v6, v4) = entries.into_iter().partition::<Vec<_>, _>(|host| matches!(host.ip.parse(), Ok(IpAddr::V6(_))));
+        vec![("# IPv4", v4), ("# IPv6", v6)]
+    } else {
+        vec![("", entries)]
+    };
+
+    let mut contents = String::from("# Generated automatically by drophost\n");
+    for (header, entries) in sections {
+        if entries.is_empty() {
+            continue;
+        }
+        if !header.is_empty() {
+            contents += &format!("\n{}\n", header);
+        }
+        for line in lines(&entries, options.group) {
+            contents += &line.render(options.provenance);
+            contents += "\n";
+        }
+    }
+    contents
+}
+
+/// Replaces the managed block of `current` with the rendered hosts, or appends
+/// it to the end of the file if there is none yet
+pub fn splice_managed_block(current: &str, hosts: &str) -> io::Result<String> {
+    let block = format!("{}\n{}{}\n", BEGIN_MARKER, hosts, END_MARKER);
+    let lines = current.lines().collect::<Vec<_>>();
+    let markers = |marker: &str| lines.iter()
+        .enumerate()
+        .filter(|(_, line)| line.trim() == marker)
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+
+    match (markers(BEGIN_MARKER).as_slice(), markers(END_MARKER).as_slice()) {
+        ([], []) => {
+            let mut contents = current.to_owned();
+            if !contents.is_empty() && !contents.ends_with('\n') {
+                contents += "\n";
+            }
+            Ok(contents + &block)
+        },
+        (&[begin], &[end]) if begin < end => {
+            let mut contents = String::new();
+            for line in &lines[..begin] {
+                contents += line;
+                contents += "\n";
+            }
+            contents += &block;
+            for line in &lines[end + 1..] {
+                contents += line;
+                contents += "\n";
+            }
+            Ok(contents)
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("expected a single '{}' line followed by a single '{}' line", BEGIN_MARKER, END_MARKER))),
+    }
+}
+
+/// Entries added and removed between two versions of a hosts file
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl Changes {
+    pub fn between(old: Vec<String>, new: Vec<String>) -> Self {
+        let mut removed = old;
+        let mut added = vec![];
+        for entry in new {
+            match removed.iter().position(|e| *e == entry) {
+                Some(index) => {
+                    removed.remove(index);
+                },
+                None => added.push(entry),
+            }
+        }
+        Changes { added, removed }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl Display for Changes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} added, {} removed", self.added.len(), self.removed.len())
+    }
+}
+
+// Entries of a hosts file in a normalized form, one per hostname, ignoring comments
+fn entries(contents: &str) -> Vec<String> {
+    let mut entries = vec![];
+    for line in contents.lines() {
+        let mut tokens = line.split('#').next().unwrap_or_default().split_whitespace();
+        if let Some(ip) = tokens.next() {
+            entries.extend(tokens.map(|hostname| Host::new(hostname.to_string(), ip.to_string()).to_string()));
+        }
+    }
+    entries
+}
+
+/// Replaces the file at `path` with the rendered hosts. The new contents are written
+/// to a temporary file in the same directory, which is then renamed over the old file,
+/// so readers only ever see the old or the new file in full. The mode and ownership
+/// of the old file are kept.
+///
+/// Nothing is written if the file already has the same contents, in which case `None`
+/// is returned.
+pub fn write_hosts_to_file(hosts: &Hosts, path: &str, options: &OutputOptions) -> io::Result<Option<Changes>> {
+    write_with_renderer(hosts, path, options.format.renderer().as_ref(), options)
+}
+
+/// Same as `write_hosts_to_file`, with a format that isn't one of `Format`
+pub fn write_with_renderer(hosts: &Hosts, path: &str, renderer: &dyn Renderer, options: &OutputOptions) -> io::Result<Option<Changes>> {
+    let current = match fs::read_to_string(path) {
+        Ok(current) => current,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let contents = match (options.managed_block, renderer.managed_block()) {
+        (true, true) => splice_managed_block(&current, &renderer.render(hosts, options))?,
+        (true, false) => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "managed block mode is not available for this format")),
+        (false, _) => renderer.render(hosts, options),
+    };
+    if current == contents {
+        return Ok(None);
+    }
+
+    write_atomically(Path::new(path), contents.as_bytes())?;
+    Ok(Some(Changes::between(renderer.entries(&current), renderer.entries(&contents))))
+}
+
+// Unique per call, so that neither a stale file left by a crash nor another writer gets in the way
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or_default();
+    let random = (nanos as u64) ^ COUNTER.fetch_add(1, Ordering::Relaxed).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{}.drophost-{}-{:x}.tmp", file_name, std::process::id(), random))
+}
+
+// Number of temporary names tried before giving up
+const TEMP_ATTEMPTS: usize = 8;
+
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    // Replace the target of a symlink rather than the link itself
+    let path = &fs::canonicalize(path).unwrap_or(path.to_owned());
+    let mut attempts = 0;
+    let (temp, res) = loop {
+        let temp = temp_path(path);
+        match write_temp(path, &temp, contents) {
+            // Someone else owns this name, don't touch it
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempts + 1 < TEMP_ATTEMPTS => attempts += 1,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(e),
+            res => break (temp.clone(), res.and_then(|_| fs::rename(&temp, path))),
+        }
+    };
+    if res.is_err() {
+        // Don't leave a half-written file behind
+        let _ = fs::remove_file(&temp);
+        return res;
+    }
+
+    // Make the rename itself durable
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+fn write_temp(path: &Path, temp: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(DEFAULT_MODE)
+        .open(temp)?;
+    file.write_all(contents)?;
+
+    match fs::metadata(path) {
+        Ok(original) => {
+            file.set_permissions(original.permissions())?;
+            let current = file.metadata()?;
+            if (current.uid(), current.gid()) != (original.uid(), original.gid()) {
+                std::os::unix::fs::fchown(&file, Some(original.uid()), Some(original.gid()))?;
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!("'{}' doesn't exist yet, creating it", path.display());
+            // The mode given to open() is restricted by the umask
+            file.set_permissions(Permissions::from_mode(DEFAULT_MODE))?;
+        },
+        Err(e) => return Err(e),
+    }
+
+    file.sync_all()
+}
@@ -0,0 +1,36 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn test_dry_run_with_root() {
+    let dir = TempDir(std::env::temp_dir().join(format!("drophost-dry-run-{}", std::process::id())));
+    let root = dir.0.join("etc");
+    let cwd = dir.0.join("cwd");
+    fs::create_dir_all(root.join("hosts.d")).unwrap();
+    fs::create_dir_all(&cwd).unwrap();
+    fs::write(root.join("hosts"), "127.0.0.1 localhost\n").unwrap();
+    fs::write(root.join("hosts.d/10-lab.conf"), "10.0.0.1 lab\n").unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_drophost"))
+        .args(["--dry-run", "--backup", "--root"])
+        .arg(&root)
+        .current_dir(&cwd)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // The configured files are left alone, the output keeps its place relative to the root
+    assert_eq!(fs::read_to_string(root.join("hosts")).unwrap(), "127.0.0.1 localhost\n");
+    assert_eq!(fs::read_dir(root.join("hosts.d")).unwrap().count(), 1);
+    assert!(fs::read_to_string(cwd.join("output/hosts")).unwrap().contains("10.0.0.1\tlab"));
+    assert_eq!(fs::read_to_string(cwd.join("output/hosts.d/10-old-config.conf")).unwrap(), "127.0.0.1 localhost\n");
+}